@@ -0,0 +1,116 @@
+//! Setting up many devices at once, e.g. a cart of classroom iPhones.
+//!
+//! Devices run through the same setup as `setup_device`, a few at a time. One
+//! device failing doesn't stop the others; every device ends up in the
+//! summary with how it went.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    companion::CompanionStatus,
+    error::AppError,
+    operations::OperationStarted,
+    setup_progress::{SetupOptions, SetupProgress},
+};
+
+/// Devices set up at the same time unless the options say otherwise.
+const DEFAULT_CONCURRENCY: usize = 4;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct BatchOptions {
+    /// How many devices to set up at the same time.
+    pub concurrency: Option<usize>,
+    /// Applied to every device.
+    #[serde(flatten)]
+    pub setup: SetupOptions,
+}
+
+impl BatchOptions {
+    pub fn concurrency(&self) -> usize {
+        self.concurrency.unwrap_or(DEFAULT_CONCURRENCY).max(1)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum DeviceOutcome {
+    /// Paired and the pairing file uploaded.
+    Succeeded,
+    /// The companion app couldn't take the pairing file, so nothing was uploaded.
+    NotReady {
+        companion: CompanionStatus,
+    },
+    Failed {
+        error: AppError,
+    },
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceResult {
+    pub udid: String,
+    #[serde(flatten)]
+    pub outcome: DeviceOutcome,
+    pub elapsed_ms: u64,
+}
+
+impl DeviceResult {
+    pub fn new(udid: String, result: Result<CompanionStatus, AppError>, elapsed: Duration) -> Self {
+        let outcome = match result {
+            Ok(companion) if companion.is_ready() => DeviceOutcome::Succeeded,
+            Ok(companion) => DeviceOutcome::NotReady { companion },
+            Err(AppError::Cancelled) => DeviceOutcome::Cancelled,
+            Err(error) => DeviceOutcome::Failed { error },
+        };
+        Self {
+            udid,
+            outcome,
+            elapsed_ms: elapsed.as_millis() as u64,
+        }
+    }
+}
+
+/// Sent on the batch's channel while it runs.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum BatchEvent {
+    /// A device's setup started under this operation id.
+    Started(OperationStarted),
+    /// A device reached the next setup stage.
+    Progress(SetupProgress),
+    /// A device is done, one way or another.
+    Finished(DeviceResult),
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchSummary {
+    pub total: usize,
+    pub succeeded: usize,
+    pub not_ready: usize,
+    pub failed: usize,
+    pub cancelled: usize,
+    pub elapsed_ms: u64,
+    /// One per device, in the order the devices were given.
+    pub results: Vec<DeviceResult>,
+}
+
+impl BatchSummary {
+    pub fn new(results: Vec<DeviceResult>, elapsed: Duration) -> Self {
+        let count =
+            |f: fn(&DeviceOutcome) -> bool| results.iter().filter(|r| f(&r.outcome)).count();
+        Self {
+            total: results.len(),
+            succeeded: count(|o| matches!(o, DeviceOutcome::Succeeded)),
+            not_ready: count(|o| matches!(o, DeviceOutcome::NotReady { .. })),
+            failed: count(|o| matches!(o, DeviceOutcome::Failed { .. })),
+            cancelled: count(|o| matches!(o, DeviceOutcome::Cancelled)),
+            elapsed_ms: elapsed.as_millis() as u64,
+            results,
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! The Auto Capture companion app the pairing file is uploaded to.
+
+use idevice::{
+    afc::AfcClient, house_arrest::HouseArrestClient, installation_proxy::InstallationProxyClient,
+    IdeviceError, IdeviceService,
+};
+use serde::Serialize;
+
+use crate::locator::DeviceLocator;
+
+/// Bundle identifier of the companion app.
+pub const COMPANION_BUNDLE_ID: &str = "com.halfeatentoast.devcapture";
+
+/// Oldest companion release that picks the pairing file up from Documents.
+pub const MIN_COMPANION_VERSION: &str = "1.0";
+
+/// What installation_proxy reports about the installed companion app.
+#[derive(Debug, Clone)]
+pub struct CompanionApp {
+    /// `CFBundleShortVersionString`, e.g. `1.2.0`.
+    pub version: Option<String>,
+}
+
+/// Whether the pairing file can be handed to the companion app.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum CompanionStatus {
+    Ready {
+        version: Option<String>,
+    },
+    NotInstalled,
+    /// Installed, but older than `MIN_COMPANION_VERSION`.
+    WrongVersion {
+        version: Option<String>,
+        minimum: String,
+    },
+    /// house_arrest refused to vend the Documents folder, usually because the
+    /// installed build doesn't enable file sharing.
+    ContainerNotVendable {
+        version: Option<String>,
+        reason: String,
+    },
+}
+
+impl CompanionStatus {
+    pub fn is_ready(&self) -> bool {
+        matches!(self, Self::Ready { .. })
+    }
+
+    /// Installed version, if the app is installed at all.
+    pub fn version(&self) -> Option<&str> {
+        match self {
+            Self::Ready { version }
+            | Self::WrongVersion { version, .. }
+            | Self::ContainerNotVendable { version, .. } => version.as_deref(),
+            Self::NotInstalled => None,
+        }
+    }
+}
+
+/// Look the companion app up on `device`. `Ok(None)` means it isn't installed.
+pub async fn lookup(device: &DeviceLocator) -> Result<Option<CompanionApp>, IdeviceError> {
+    let mut ip = InstallationProxyClient::connect(device.provider()).await?;
+    let apps = ip
+        .get_apps(Some("User"), Some(vec![COMPANION_BUNDLE_ID.to_string()]))
+        .await?;
+    let Some(app) = apps
+        .get(COMPANION_BUNDLE_ID)
+        .and_then(|a| a.as_dictionary())
+    else {
+        return Ok(None);
+    };
+
+    Ok(Some(CompanionApp {
+        version: app
+            .get("CFBundleShortVersionString")
+            .and_then(|v| v.as_string())
+            .map(|v| v.to_string()),
+    }))
+}
+
+/// Check that the companion app is installed, recent enough and has a
+/// Documents folder house_arrest will vend.
+pub async fn verify(device: &DeviceLocator) -> Result<CompanionStatus, IdeviceError> {
+    let Some(app) = lookup(device).await? else {
+        log::info!(
+            "verify_companion: {} is not installed on {}",
+            COMPANION_BUNDLE_ID,
+            device.udid()
+        );
+        return Ok(CompanionStatus::NotInstalled);
+    };
+    let version = app.version;
+    if !version
+        .as_deref()
+        .is_some_and(|v| version_at_least(v, MIN_COMPANION_VERSION))
+    {
+        return Ok(CompanionStatus::WrongVersion {
+            version,
+            minimum: MIN_COMPANION_VERSION.to_string(),
+        });
+    }
+
+    match documents(device).await {
+        Ok(_) => Ok(CompanionStatus::Ready { version }),
+        // transport failures say nothing about the app itself
+        Err(e @ (IdeviceError::Socket(_) | IdeviceError::Usbmuxd(_))) => Err(e),
+        Err(e) => {
+            log::warn!(
+                "verify_companion: cannot vend documents on {}: {:?}",
+                device.udid(),
+                e
+            );
+            Ok(CompanionStatus::ContainerNotVendable {
+                version,
+                reason: e.to_string(),
+            })
+        }
+    }
+}
+
+/// AFC client rooted at the companion app's container, with `/Documents` in it.
+pub async fn documents(device: &DeviceLocator) -> Result<AfcClient, IdeviceError> {
+    HouseArrestClient::connect(device.provider())
+        .await?
+        .vend_documents(COMPANION_BUNDLE_ID)
+        .await
+}
+
+/// Compare dotted versions numerically, treating missing components as 0.
+fn version_at_least(version: &str, minimum: &str) -> bool {
+    let parse = |v: &str| -> Vec<u64> {
+        v.split('.')
+            .map(|part| part.trim().parse().unwrap_or(0))
+            .collect()
+    };
+    let (version, minimum) = (parse(version), parse(minimum));
+    let len = version.len().max(minimum.len());
+    let at = |v: &[u64], i: usize| v.get(i).copied().unwrap_or(0);
+    (0..len)
+        .map(|i| (at(&version, i), at(&minimum, i)))
+        .find(|(v, m)| v != m)
+        .is_none_or(|(v, m)| v > m)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_comparison() {
+        let cases = [
+            ("1.2.0", "1.2.0", true),
+            ("1.2", "1.2.0", true),
+            ("1.2.0", "1.2", true),
+            ("1.10", "1.9", true),
+            ("2.0", "1.99.99", true),
+            ("1.2.1", "1.2", true),
+            ("1.1.9", "1.2", false),
+            ("1.9", "1.10", false),
+            ("1", "1.0.1", false),
+            (" 1.2 ", "1.2", true),
+            ("1.x", "1.0", true),
+            ("1.x", "1.1", false),
+            ("", "0", true),
+            ("", "0.1", false),
+        ];
+        for (version, minimum, expected) in cases {
+            assert_eq!(
+                version_at_least(version, minimum),
+                expected,
+                "{:?} at least {:?}",
+                version,
+                minimum
+            );
+        }
+    }
+}
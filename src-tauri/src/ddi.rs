@@ -0,0 +1,339 @@
+//! Developer Disk Image mounting through mobile_image_mounter.
+//!
+//! Images come from a directory the user points us at. Devices before iOS 17
+//! take the classic image:
+//!
+//! ```text
+//! DeveloperDiskImage.dmg
+//! DeveloperDiskImage.dmg.signature
+//! ```
+//!
+//! either directly in the directory or in a `<major>.<minor>` subdirectory.
+//! iOS 17 and later take the personalized image:
+//!
+//! ```text
+//! Image.dmg
+//! Image.dmg.trustcache
+//! BuildManifest.plist
+//! ApImg4Ticket.der      (optional)
+//! ```
+//!
+//! The personalized image is signed for each device by Apple's TSS server,
+//! unless the device already holds a manifest for it. idevice has the TSS
+//! endpoint compiled in, so a local TSS server can't be swapped in for it.
+//! Instead, a signed ticket saved as `ApImg4Ticket.der` replaces the TSS round
+//! trip: that is where a local TSS stand-in, or a ticket captured earlier,
+//! plugs in without reaching Apple.
+
+use std::path::{Path, PathBuf};
+
+use idevice::{
+    lockdown::LockdownClient, mobile_image_mounter::ImageMounter, IdeviceError, IdeviceService,
+};
+use serde::Serialize;
+
+use crate::{device_info, error::AppError, locator::DeviceLocator};
+
+/// Event emitted as a mount moves through its stages.
+pub const DDI_PROGRESS_EVENT: &str = "ddi-progress";
+
+const CLASSIC_IMAGE: &str = "DeveloperDiskImage.dmg";
+const CLASSIC_SIGNATURE: &str = "DeveloperDiskImage.dmg.signature";
+const PERSONALIZED_IMAGE: &str = "Image.dmg";
+const PERSONALIZED_TRUST_CACHE: &str = "Image.dmg.trustcache";
+const BUILD_MANIFEST: &str = "BuildManifest.plist";
+const LOCAL_TICKET: &str = "ApImg4Ticket.der";
+
+/// First iOS major version that only accepts personalized images.
+const PERSONALIZED_MIN_MAJOR: u64 = 17;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DdiStage {
+    /// Looking for an image that is already mounted.
+    Checking,
+    /// Fetching the device specific manifest (from the device, a local ticket or TSS).
+    Personalizing,
+    /// Sending the image to the device; see `sent` / `total`.
+    Uploading,
+    Mounting,
+    Mounted,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DdiProgress {
+    pub stage: DdiStage,
+    /// Bytes uploaded so far, while `Uploading`.
+    pub sent: Option<u64>,
+    pub total: Option<u64>,
+}
+
+impl DdiProgress {
+    fn stage(stage: DdiStage) -> Self {
+        Self {
+            stage,
+            sent: None,
+            total: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DdiProgressEvent {
+    pub udid: String,
+    #[serde(flatten)]
+    pub progress: DdiProgress,
+}
+
+/// Where the personalized image's signed ticket comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TicketSource {
+    /// `ApImg4Ticket.der` in the image directory.
+    Local(PathBuf),
+    /// The device's cached manifest, or Apple's TSS server.
+    Tss,
+}
+
+fn ticket_source(image_dir: &Path) -> TicketSource {
+    let ticket_path = image_dir.join(LOCAL_TICKET);
+    if ticket_path.is_file() {
+        TicketSource::Local(ticket_path)
+    } else {
+        TicketSource::Tss
+    }
+}
+
+/// What `mount_developer_image` did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MountOutcome {
+    AlreadyMounted,
+    Mounted,
+}
+
+/// Make sure a developer disk image is mounted on `device`, mounting the
+/// matching one from `image_dir` if not.
+pub async fn mount_developer_image(
+    device: &DeviceLocator,
+    image_dir: &Path,
+    on_progress: impl Fn(DdiProgress),
+) -> Result<MountOutcome, AppError> {
+    let udid = device.udid();
+    log::info!("mount_developer_image: starting for udid={}", udid);
+    on_progress(DdiProgress::stage(DdiStage::Checking));
+
+    // The mounter connection has to be open before lockdown is queried, or
+    // the device stops answering it
+    let mut mounter = ImageMounter::connect(device.provider()).await?;
+    let pairing_file = device.pair_record().await?;
+    let mut lc = LockdownClient::connect(device.provider()).await?;
+    lc.start_session(&pairing_file).await?;
+    let version = lc
+        .get_value(Some("ProductVersion"), None)
+        .await?
+        .as_string()
+        .map(|v| v.to_string())
+        .ok_or_else(|| IdeviceError::UnexpectedResponse("missing ProductVersion".into()))?;
+    let personalized = device_info::ios_major_version(&version) >= PERSONALIZED_MIN_MAJOR;
+    log::debug!(
+        "mount_developer_image: {} runs iOS {} ({} image)",
+        udid,
+        version,
+        if personalized {
+            "personalized"
+        } else {
+            "classic"
+        }
+    );
+
+    let image_type = if personalized {
+        "Personalized"
+    } else {
+        "Developer"
+    };
+    match mounter.lookup_image(image_type).await {
+        Ok(_) => {
+            log::info!(
+                "mount_developer_image: {} already has an image mounted",
+                udid
+            );
+            return Ok(MountOutcome::AlreadyMounted);
+        }
+        Err(IdeviceError::NotFound) => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let upload_progress = |((sent, total), ()): ((usize, usize), ())| {
+        on_progress(DdiProgress {
+            stage: DdiStage::Uploading,
+            sent: Some(sent as u64),
+            total: Some(total as u64),
+        });
+        async {}
+    };
+
+    if personalized {
+        let image = read(image_dir, PERSONALIZED_IMAGE)?;
+        let trust_cache = read(image_dir, PERSONALIZED_TRUST_CACHE)?;
+        on_progress(DdiProgress::stage(DdiStage::Personalizing));
+
+        if let TicketSource::Local(ticket_path) = ticket_source(image_dir) {
+            log::info!(
+                "mount_developer_image: using local ticket {}",
+                ticket_path.display()
+            );
+            let ticket = read(image_dir, LOCAL_TICKET)?;
+            mounter
+                .upload_image_with_progress(
+                    "Personalized",
+                    &image,
+                    ticket.clone(),
+                    upload_progress,
+                    (),
+                )
+                .await?;
+            on_progress(DdiProgress::stage(DdiStage::Mounting));
+            mounter
+                .mount_image("Personalized", ticket, Some(trust_cache), None)
+                .await?;
+        } else {
+            let build_manifest = read(image_dir, BUILD_MANIFEST)?;
+            let chip_id = lc
+                .get_value(Some("UniqueChipID"), None)
+                .await?
+                .as_unsigned_integer()
+                .ok_or_else(|| IdeviceError::UnexpectedResponse("missing UniqueChipID".into()))?;
+            // Uses the device's cached manifest if it has one, otherwise asks
+            // TSS, then uploads and mounts
+            mounter
+                .mount_personalized_with_callback(
+                    device.provider(),
+                    image,
+                    trust_cache,
+                    &build_manifest,
+                    None,
+                    chip_id,
+                    upload_progress,
+                    (),
+                )
+                .await?;
+        }
+    } else {
+        let dir = classic_image_dir(image_dir, &version)?;
+        let image = read(&dir, CLASSIC_IMAGE)?;
+        let signature = read(&dir, CLASSIC_SIGNATURE)?;
+        mounter
+            .upload_image_with_progress("Developer", &image, signature.clone(), upload_progress, ())
+            .await?;
+        on_progress(DdiProgress::stage(DdiStage::Mounting));
+        mounter
+            .mount_image("Developer", signature, None, None)
+            .await?;
+    }
+
+    log::info!("mount_developer_image: mounted image on {}", udid);
+    on_progress(DdiProgress::stage(DdiStage::Mounted));
+    Ok(MountOutcome::Mounted)
+}
+
+/// The directory holding the classic image for `version`: `image_dir` itself,
+/// or its `<major>.<minor>` / `<major>` subdirectory.
+fn classic_image_dir(image_dir: &Path, version: &str) -> Result<PathBuf, AppError> {
+    let major_minor: String = version.split('.').take(2).collect::<Vec<_>>().join(".");
+    let major = version.split('.').next().unwrap_or_default();
+    [
+        image_dir.to_path_buf(),
+        image_dir.join(&major_minor),
+        image_dir.join(major),
+    ]
+    .into_iter()
+    .find(|dir| dir.join(CLASSIC_IMAGE).is_file())
+    .ok_or_else(|| {
+        AppError::InvalidInput(format!(
+            "no {} for iOS {} in {}",
+            CLASSIC_IMAGE,
+            version,
+            image_dir.display()
+        ))
+    })
+}
+
+fn read(dir: &Path, name: &str) -> Result<Vec<u8>, AppError> {
+    let path = dir.join(name);
+    std::fs::read(&path)
+        .map_err(|e| AppError::Io(format!("failed to read {}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A scratch image directory holding `files`, removed when dropped.
+    struct ImageDir(PathBuf);
+
+    impl ImageDir {
+        fn new(files: &[&str]) -> Self {
+            let dir = std::env::temp_dir().join(format!("ddi-test-{}", uuid::Uuid::new_v4()));
+            for file in files {
+                let path = dir.join(file);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, b"image").unwrap();
+            }
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for ImageDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn classic_image_dir_lookup() {
+        let cases: &[(&[&str], &str, Option<&str>)] = &[
+            (&["DeveloperDiskImage.dmg"], "16.7.2", Some("")),
+            (&["16.7/DeveloperDiskImage.dmg"], "16.7.2", Some("16.7")),
+            (&["16/DeveloperDiskImage.dmg"], "16.7.2", Some("16")),
+            (
+                &["DeveloperDiskImage.dmg", "16.7/DeveloperDiskImage.dmg"],
+                "16.7",
+                Some(""),
+            ),
+            (&["15.8/DeveloperDiskImage.dmg"], "16.7.2", None),
+            (&[], "16.7.2", None),
+        ];
+        for (files, version, expected) in cases {
+            let dir = ImageDir::new(files);
+            let found = classic_image_dir(&dir.0, version).ok();
+            assert_eq!(
+                found,
+                expected.map(|sub| if sub.is_empty() {
+                    dir.0.clone()
+                } else {
+                    dir.0.join(sub)
+                }),
+                "{:?} for iOS {}",
+                files,
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn local_ticket_replaces_tss() {
+        let dir = ImageDir::new(&[PERSONALIZED_IMAGE, LOCAL_TICKET]);
+        assert_eq!(
+            ticket_source(&dir.0),
+            TicketSource::Local(dir.0.join(LOCAL_TICKET))
+        );
+        assert_eq!(read(&dir.0, LOCAL_TICKET).unwrap(), b"image");
+
+        let dir = ImageDir::new(&[PERSONALIZED_IMAGE]);
+        assert_eq!(ticket_source(&dir.0), TicketSource::Tss);
+        assert!(read(&dir.0, LOCAL_TICKET).is_err());
+    }
+}
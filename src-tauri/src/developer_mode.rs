@@ -0,0 +1,157 @@
+//! Developer mode enablement, driven through AMFI.
+//!
+//! Revealing the Settings toggle, enabling, surviving the reboot and
+//! accepting the post-reboot prompt are one workflow here. Each step the user
+//! can see is reported through `on_stage` so the frontend can tell them what
+//! to do next.
+
+use std::time::{Duration, Instant};
+
+use idevice::{amfi::AmfiClient, IdeviceError, IdeviceService};
+use serde::Serialize;
+
+use crate::{
+    error::AppError,
+    locator::{DeviceLocator, Route},
+    muxer,
+};
+
+/// Event emitted as developer mode enablement moves between stages.
+pub const DEVELOPER_MODE_STAGE_EVENT: &str = "developer-mode-stage";
+
+/// Covers the reboot plus the user unlocking and confirming afterwards.
+pub const DEVELOPER_MODE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Delay between usbmuxd / AMFI polls while waiting on the device.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DeveloperModeStage {
+    /// The Developer Mode toggle is now shown in Settings > Privacy & Security.
+    Revealed,
+    /// Enabling was accepted; the device is restarting.
+    AwaitingReboot,
+    /// The device is back; unlock it and confirm "Turn On Developer Mode".
+    AwaitingConfirmation,
+    /// Developer mode is on.
+    Enabled,
+    /// AMFI won't enable developer mode remotely while a passcode is set, so the
+    /// user has to flip the toggle in Settings themselves (the device then restarts).
+    BlockedByPasscode,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeveloperModeStageEvent {
+    pub udid: String,
+    pub stage: DeveloperModeStage,
+}
+
+/// Turn developer mode on for `device` and wait until AMFI reports it enabled.
+///
+/// Returns straight away (after reporting `Enabled`) when it already is.
+pub async fn enable_developer_mode(
+    device: &DeviceLocator,
+    timeout: Duration,
+    on_stage: impl Fn(DeveloperModeStage),
+) -> Result<(), AppError> {
+    let udid = device.udid();
+    log::info!("enable_developer_mode: starting for udid={}", udid);
+    let deadline = Instant::now() + timeout;
+
+    let mut last_stage = None;
+    let stage = |s: DeveloperModeStage, last: &mut Option<DeveloperModeStage>| {
+        if *last != Some(s) {
+            log::info!("enable_developer_mode: {} is now {:?}", udid, s);
+            *last = Some(s);
+            on_stage(s);
+        }
+    };
+
+    let mut amfi = AmfiClient::connect(device.provider()).await?;
+    if amfi.get_developer_mode_status().await? {
+        stage(DeveloperModeStage::Enabled, &mut last_stage);
+        return Ok(());
+    }
+
+    amfi.reveal_developer_mode_option_in_ui().await?;
+    stage(DeveloperModeStage::Revealed, &mut last_stage);
+
+    // amfid answers without a success key when it refuses, which it does
+    // whenever a passcode is set
+    match amfi.enable_developer_mode().await {
+        Ok(()) => stage(DeveloperModeStage::AwaitingReboot, &mut last_stage),
+        Err(IdeviceError::UnexpectedResponse(e)) => {
+            log::info!(
+                "enable_developer_mode: {} refused to enable remotely: {}",
+                udid,
+                e
+            );
+            stage(DeveloperModeStage::BlockedByPasscode, &mut last_stage);
+        }
+        Err(e) => return Err(e.into()),
+    }
+    drop(amfi);
+
+    // Either way the device restarts before developer mode takes effect:
+    // wait for it to drop off usbmuxd, then come back
+    while is_attached(udid).await? {
+        wait(deadline).await?;
+    }
+    log::info!(
+        "enable_developer_mode: {} went away, waiting for it to return",
+        udid
+    );
+    if last_stage == Some(DeveloperModeStage::BlockedByPasscode) {
+        stage(DeveloperModeStage::AwaitingReboot, &mut last_stage);
+    }
+    while !is_attached(udid).await? {
+        wait(deadline).await?;
+    }
+
+    // The device id changes across a reboot, so resolve it again. AMFI is
+    // unreachable until the device has booted and been unlocked once.
+    let mut accepted = false;
+    loop {
+        match confirm(udid, &mut accepted).await {
+            Ok(true) => {
+                stage(DeveloperModeStage::Enabled, &mut last_stage);
+                return Ok(());
+            }
+            Ok(false) => stage(DeveloperModeStage::AwaitingConfirmation, &mut last_stage),
+            Err(e) => log::debug!("enable_developer_mode: {} not ready yet: {:?}", udid, e),
+        }
+        wait(deadline).await?;
+    }
+}
+
+/// Check the status after the reboot, asking the device to show the
+/// confirmation prompt the first time it will take the request.
+async fn confirm(udid: &str, accepted: &mut bool) -> Result<bool, IdeviceError> {
+    let device = DeviceLocator::locate(udid, Route::Any, "enable_developer_mode").await?;
+    let mut amfi = AmfiClient::connect(device.provider()).await?;
+    if amfi.get_developer_mode_status().await? {
+        return Ok(true);
+    }
+    if !*accepted {
+        amfi.accept_developer_mode().await?;
+        *accepted = true;
+    }
+    Ok(false)
+}
+
+async fn is_attached(udid: &str) -> Result<bool, IdeviceError> {
+    let devices = muxer::connect().await?.get_devices().await?;
+    Ok(devices.iter().any(|d| d.udid == udid))
+}
+
+async fn wait(deadline: Instant) -> Result<(), AppError> {
+    if Instant::now() + POLL_INTERVAL >= deadline {
+        return Err(AppError::Timeout(
+            "waiting for developer mode to be enabled".into(),
+        ));
+    }
+    tokio::time::sleep(POLL_INTERVAL).await;
+    Ok(())
+}
@@ -0,0 +1,311 @@
+//! Per-device descriptor returned to the frontend.
+//!
+//! Everything here comes from lockdown `GetValue`. When lockdown can't be
+//! reached the device is still reported, with `error` describing why.
+
+use std::time::Duration;
+
+use idevice::{
+    lockdown::LockdownClient,
+    provider::IdeviceProvider,
+    usbmuxd::{Connection, UsbmuxdDevice},
+    IdeviceError, IdeviceService,
+};
+use serde::Serialize;
+
+use crate::{locator::DeviceLocator, wifi_debugging};
+
+/// How long a single device gets to answer lockdown before it is reported as timed out.
+pub const DEVICE_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How the device is reachable through usbmuxd.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ConnectionType {
+    Usb,
+    Network,
+    Unknown,
+}
+
+/// Whether this host holds a pair record the device accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TrustState {
+    Trusted,
+    NotTrusted,
+    Unknown,
+}
+
+/// Why a device's descriptor is incomplete.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DeviceErrorKind {
+    /// Lockdown didn't answer within `DEVICE_QUERY_TIMEOUT`.
+    Timeout,
+    /// The device is passcode locked.
+    Locked,
+    /// Lockdown refused the connection or the session.
+    LockdownRefused,
+    Other,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceError {
+    pub kind: DeviceErrorKind,
+    pub message: String,
+}
+
+impl From<&IdeviceError> for DeviceError {
+    fn from(e: &IdeviceError) -> Self {
+        let kind = match e {
+            IdeviceError::DeviceLocked | IdeviceError::PasswordProtected => DeviceErrorKind::Locked,
+            IdeviceError::Socket(_)
+            | IdeviceError::Usbmuxd(_)
+            | IdeviceError::GetProhibited
+            | IdeviceError::InvalidHostID
+            | IdeviceError::SessionInactive
+            | IdeviceError::NoEstablishedConnection => DeviceErrorKind::LockdownRefused,
+            _ => DeviceErrorKind::Other,
+        };
+        Self {
+            kind,
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Developer mode as reported by the `com.apple.security.mac.amfi` domain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DeveloperModeState {
+    Enabled,
+    Disabled,
+    /// Not readable, either because the device isn't trusted or the iOS
+    /// version predates developer mode.
+    Unknown,
+}
+
+/// Wi-Fi debugging as reported by the `com.apple.mobile.wireless_lockdown` domain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum WifiDebuggingState {
+    Enabled,
+    Disabled,
+    /// Not readable without a session, i.e. the device isn't trusted.
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceInfo {
+    pub udid: String,
+    pub name: Option<String>,
+    /// Hardware identifier such as `iPhone15,2`.
+    pub product_type: Option<String>,
+    /// Human readable model such as `iPhone 14 Pro`, when `product_type` is known.
+    pub model_name: Option<String>,
+    pub product_version: Option<String>,
+    pub build_version: Option<String>,
+    pub serial_number: Option<String>,
+    /// Preferred route: USB when plugged in, otherwise network.
+    pub connection_type: ConnectionType,
+    /// Network address when the device is (also) visible over Wi-Fi.
+    pub address: Option<String>,
+    pub trust: TrustState,
+    pub developer_mode: DeveloperModeState,
+    pub wifi_debugging: WifiDebuggingState,
+    /// Set when lockdown could not be queried; the other fields may be empty.
+    pub error: Option<DeviceError>,
+}
+
+impl DeviceInfo {
+    /// A descriptor with only what usbmuxd knows about the device.
+    pub fn from_usbmuxd(dev: &UsbmuxdDevice) -> Self {
+        let mut info = Self {
+            udid: dev.udid.clone(),
+            name: None,
+            product_type: None,
+            model_name: None,
+            product_version: None,
+            build_version: None,
+            serial_number: None,
+            connection_type: ConnectionType::Unknown,
+            address: None,
+            trust: TrustState::Unknown,
+            developer_mode: DeveloperModeState::Unknown,
+            wifi_debugging: WifiDebuggingState::Unknown,
+            error: None,
+        };
+        info.set_routes([dev]);
+        info
+    }
+
+    /// Refresh the connection fields from every usbmuxd entry for this device.
+    pub fn set_routes<'a>(&mut self, routes: impl IntoIterator<Item = &'a UsbmuxdDevice>) {
+        self.connection_type = ConnectionType::Unknown;
+        self.address = None;
+        for route in routes {
+            match &route.connection_type {
+                Connection::Usb => self.connection_type = ConnectionType::Usb,
+                Connection::Network(addr) => {
+                    if self.connection_type != ConnectionType::Usb {
+                        self.connection_type = ConnectionType::Network;
+                    }
+                    self.address = Some(addr.to_string());
+                }
+                Connection::Unknown(_) => {}
+            }
+        }
+    }
+}
+
+/// Build a full descriptor for `dev`. Never fails; lockdown errors and
+/// timeouts end up in `DeviceInfo::error`.
+pub async fn query_device_info(dev: &UsbmuxdDevice) -> DeviceInfo {
+    let mut info = DeviceInfo::from_usbmuxd(dev);
+    match tokio::time::timeout(DEVICE_QUERY_TIMEOUT, fill_from_lockdown(dev, &mut info)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            log::warn!("Failed to query lockdown for {}: {e:?}", dev.udid);
+            info.error = Some(DeviceError::from(&e));
+        }
+        Err(_) => {
+            log::warn!("Timed out querying lockdown for {}", dev.udid);
+            info.error = Some(DeviceError {
+                kind: DeviceErrorKind::Timeout,
+                message: format!(
+                    "device did not answer within {}s",
+                    DEVICE_QUERY_TIMEOUT.as_secs()
+                ),
+            });
+        }
+    }
+    info
+}
+
+async fn fill_from_lockdown(
+    dev: &UsbmuxdDevice,
+    info: &mut DeviceInfo,
+) -> Result<(), IdeviceError> {
+    let device = DeviceLocator::new(dev.clone(), "query_device_info");
+    let provider = device.provider();
+    let mut lc = LockdownClient::connect(provider).await?;
+
+    // A session unlocks the full value set. Without a usable pair record
+    // lockdown still answers the basic identity keys.
+    info.trust = match provider.get_pairing_file().await {
+        Ok(pairing_file) => match lc.start_session(&pairing_file).await {
+            Ok(()) => TrustState::Trusted,
+            Err(IdeviceError::InvalidHostID) => {
+                // the rejected StartSession leaves the connection in an odd
+                // state, so start over without a session
+                lc = LockdownClient::connect(provider).await?;
+                TrustState::NotTrusted
+            }
+            Err(e) => return Err(e),
+        },
+        Err(_) => TrustState::NotTrusted,
+    };
+
+    let values = lc.get_value(None, None).await?;
+    let values = values.as_dictionary().ok_or_else(|| {
+        IdeviceError::UnexpectedResponse("lockdown values are not a dictionary".into())
+    })?;
+    let string = |key: &str| {
+        values
+            .get(key)
+            .and_then(|v| v.as_string())
+            .map(|v| v.to_string())
+    };
+
+    info.name = string("DeviceName");
+    info.product_type = string("ProductType");
+    info.model_name = info
+        .product_type
+        .as_deref()
+        .and_then(marketing_name)
+        .map(|name| name.to_string());
+    info.product_version = string("ProductVersion");
+    info.build_version = string("BuildVersion");
+    info.serial_number = string("SerialNumber");
+
+    if info.trust == TrustState::Trusted {
+        info.developer_mode = match lc
+            .get_value(
+                Some("DeveloperModeStatus"),
+                Some("com.apple.security.mac.amfi"),
+            )
+            .await
+            .ok()
+            .and_then(|v| v.as_boolean())
+        {
+            Some(true) => DeveloperModeState::Enabled,
+            Some(false) => DeveloperModeState::Disabled,
+            None => DeveloperModeState::Unknown,
+        };
+        info.wifi_debugging = match wifi_debugging::read(&mut lc).await {
+            Ok(true) => WifiDebuggingState::Enabled,
+            Ok(false) => WifiDebuggingState::Disabled,
+            Err(_) => WifiDebuggingState::Unknown,
+        };
+    }
+
+    Ok(())
+}
+
+/// Major version of an iOS `ProductVersion` such as `17.4.1`, 0 if unparsable.
+pub fn ios_major_version(version: &str) -> u64 {
+    version
+        .split('.')
+        .next()
+        .and_then(|major| major.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Map a `ProductType` to the name Apple markets the device under.
+pub fn marketing_name(product_type: &str) -> Option<&'static str> {
+    Some(match product_type {
+        "iPhone11,2" => "iPhone XS",
+        "iPhone11,4" | "iPhone11,6" => "iPhone XS Max",
+        "iPhone11,8" => "iPhone XR",
+        "iPhone12,1" => "iPhone 11",
+        "iPhone12,3" => "iPhone 11 Pro",
+        "iPhone12,5" => "iPhone 11 Pro Max",
+        "iPhone12,8" => "iPhone SE (2nd generation)",
+        "iPhone13,1" => "iPhone 12 mini",
+        "iPhone13,2" => "iPhone 12",
+        "iPhone13,3" => "iPhone 12 Pro",
+        "iPhone13,4" => "iPhone 12 Pro Max",
+        "iPhone14,2" => "iPhone 13 Pro",
+        "iPhone14,3" => "iPhone 13 Pro Max",
+        "iPhone14,4" => "iPhone 13 mini",
+        "iPhone14,5" => "iPhone 13",
+        "iPhone14,6" => "iPhone SE (3rd generation)",
+        "iPhone14,7" => "iPhone 14",
+        "iPhone14,8" => "iPhone 14 Plus",
+        "iPhone15,2" => "iPhone 14 Pro",
+        "iPhone15,3" => "iPhone 14 Pro Max",
+        "iPhone15,4" => "iPhone 15",
+        "iPhone15,5" => "iPhone 15 Plus",
+        "iPhone16,1" => "iPhone 15 Pro",
+        "iPhone16,2" => "iPhone 15 Pro Max",
+        "iPhone17,1" => "iPhone 16 Pro",
+        "iPhone17,2" => "iPhone 16 Pro Max",
+        "iPhone17,3" => "iPhone 16",
+        "iPhone17,4" => "iPhone 16 Plus",
+        "iPhone17,5" => "iPhone 16e",
+        "iPhone18,1" => "iPhone 17 Pro",
+        "iPhone18,2" => "iPhone 17 Pro Max",
+        "iPhone18,3" => "iPhone 17",
+        "iPhone18,4" => "iPhone Air",
+        "iPad13,18" | "iPad13,19" => "iPad (10th generation)",
+        "iPad14,1" | "iPad14,2" => "iPad mini (6th generation)",
+        "iPad13,16" | "iPad13,17" => "iPad Air (5th generation)",
+        "iPad14,8" | "iPad14,9" => "iPad Air 11-inch (M2)",
+        "iPad14,10" | "iPad14,11" => "iPad Air 13-inch (M2)",
+        "iPad16,3" | "iPad16,4" => "iPad Pro 11-inch (M4)",
+        "iPad16,5" | "iPad16,6" => "iPad Pro 13-inch (M4)",
+        _ => return None,
+    })
+}
@@ -0,0 +1,257 @@
+//! Live device table driven by usbmuxd attach/detach notifications.
+//!
+//! The watcher keeps a `Listen` connection open to usbmuxd for the lifetime of
+//! the app, mirrors every attached device (USB or Wi-Fi) into Tauri managed state and
+//! emits `device-attached` / `device-detached` / `device-updated` events so
+//! the frontend never has to poll.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use futures::StreamExt;
+use idevice::usbmuxd::{UsbmuxdDevice, UsbmuxdListenEvent};
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::sync::Notify;
+
+use crate::{
+    device_info::{self, DeviceInfo},
+    locator, muxer,
+};
+
+pub const DEVICE_ATTACHED_EVENT: &str = "device-attached";
+pub const DEVICE_DETACHED_EVENT: &str = "device-detached";
+pub const DEVICE_UPDATED_EVENT: &str = "device-updated";
+
+/// How long to wait before reconnecting after the usbmuxd listen stream drops.
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+/// Managed state holding the live device table.
+#[derive(Default)]
+pub struct DeviceWatcher {
+    table: Mutex<DeviceTable>,
+    started: AtomicBool,
+    // wakes the listen loop so it reconnects, e.g. after the usbmuxd endpoint changed
+    reconnect: Notify,
+}
+
+#[derive(Default)]
+struct DeviceTable {
+    // every usbmuxd entry, keyed by device id because detach notifications only carry the id
+    routes: HashMap<u32, UsbmuxdDevice>,
+    // one descriptor per UDID, even when the device is visible over USB and Wi-Fi
+    devices: HashMap<String, DeviceInfo>,
+}
+
+impl DeviceTable {
+    fn routes_for(&self, udid: &str) -> Vec<&UsbmuxdDevice> {
+        self.routes.values().filter(|r| r.udid == udid).collect()
+    }
+}
+
+/// What a usbmuxd notification did to the table, i.e. which event to emit.
+enum Change {
+    Attached(DeviceInfo),
+    Updated(DeviceInfo),
+    Detached(DeviceInfo),
+}
+
+impl DeviceWatcher {
+    /// Current devices, sorted by UDID so the dropdown order is stable.
+    pub fn snapshot(&self) -> Vec<DeviceInfo> {
+        let mut devices: Vec<DeviceInfo> = self
+            .table
+            .lock()
+            .unwrap()
+            .devices
+            .values()
+            .cloned()
+            .collect();
+        devices.sort_by(|a, b| a.udid.cmp(&b.udid));
+        devices
+    }
+
+    /// Current descriptor for `udid`, if it is attached.
+    pub fn device(&self, udid: &str) -> Option<DeviceInfo> {
+        self.table.lock().unwrap().devices.get(udid).cloned()
+    }
+
+    fn attach(&self, dev: UsbmuxdDevice) -> Change {
+        let mut table = self.table.lock().unwrap();
+        let udid = dev.udid.clone();
+        table.routes.insert(dev.device_id, dev);
+
+        let routes: Vec<UsbmuxdDevice> = table.routes_for(&udid).into_iter().cloned().collect();
+        match table.devices.get_mut(&udid) {
+            Some(info) => {
+                info.set_routes(&routes);
+                Change::Updated(info.clone())
+            }
+            None => {
+                let mut info = DeviceInfo::from_usbmuxd(&routes[0]);
+                info.set_routes(&routes);
+                table.devices.insert(udid, info.clone());
+                Change::Attached(info)
+            }
+        }
+    }
+
+    fn detach(&self, device_id: u32) -> Option<Change> {
+        let mut table = self.table.lock().unwrap();
+        let route = table.routes.remove(&device_id)?;
+
+        let routes: Vec<UsbmuxdDevice> =
+            table.routes_for(&route.udid).into_iter().cloned().collect();
+        if routes.is_empty() {
+            return table.devices.remove(&route.udid).map(Change::Detached);
+        }
+        let info = table.devices.get_mut(&route.udid)?;
+        info.set_routes(&routes);
+        Some(Change::Updated(info.clone()))
+    }
+
+    /// The route descriptors for `udid` should be queried over.
+    fn route(&self, udid: &str) -> Option<UsbmuxdDevice> {
+        let table = self.table.lock().unwrap();
+        locator::select_device(table.routes_for(udid), udid).cloned()
+    }
+
+    fn drain(&self) -> Vec<DeviceInfo> {
+        let mut table = self.table.lock().unwrap();
+        table.routes.clear();
+        table.devices.drain().map(|(_, d)| d).collect()
+    }
+
+    /// Store a freshly queried descriptor, keeping the current routes. Returns
+    /// `None` if the device has detached meanwhile.
+    fn update(&self, mut device: DeviceInfo) -> Option<DeviceInfo> {
+        let mut table = self.table.lock().unwrap();
+        let routes: Vec<UsbmuxdDevice> = table
+            .routes_for(&device.udid)
+            .into_iter()
+            .cloned()
+            .collect();
+        let entry = table.devices.get_mut(&device.udid)?;
+        device.set_routes(&routes);
+        *entry = device.clone();
+        Some(device)
+    }
+}
+
+fn emit(app: &AppHandle, change: Change) {
+    let _ = match change {
+        Change::Attached(device) => app.emit(DEVICE_ATTACHED_EVENT, &device),
+        Change::Updated(device) => app.emit(DEVICE_UPDATED_EVENT, &device),
+        Change::Detached(device) => app.emit(DEVICE_DETACHED_EVENT, &device),
+    };
+}
+
+/// Start the background listener. Calling this more than once is a no-op.
+pub fn start(app: AppHandle) {
+    let watcher = app.state::<DeviceWatcher>();
+    if watcher.started.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    // The usbmuxd listen stream isn't `Send`, so it gets its own thread and is
+    // driven from there instead of being spawned onto the runtime.
+    let spawned = std::thread::Builder::new()
+        .name("device-watcher".into())
+        .spawn(move || tauri::async_runtime::block_on(watch(app)));
+    if let Err(e) = spawned {
+        log::error!("device_watcher: failed to start watcher thread: {e:?}");
+    }
+}
+
+/// Drop the current usbmuxd connection and listen again from scratch.
+pub fn reconnect(app: &AppHandle) {
+    app.state::<DeviceWatcher>().reconnect.notify_one();
+}
+
+async fn watch(app: AppHandle) {
+    loop {
+        if let Err(e) = listen(&app).await {
+            log::warn!("device_watcher: usbmuxd listen failed: {e:?}");
+        }
+
+        // The listen stream is gone, so anything in the table may be stale.
+        // usbmuxd re-sends Attached for every device once we reconnect.
+        for device in app.state::<DeviceWatcher>().drain() {
+            let _ = app.emit(DEVICE_DETACHED_EVENT, &device);
+        }
+
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn listen(app: &AppHandle) -> Result<(), idevice::IdeviceError> {
+    log::debug!("device_watcher: connecting to usbmuxd");
+    let mut uc = muxer::connect().await?;
+    let mut events = uc.listen().await?;
+    log::info!("device_watcher: listening for usbmuxd events");
+
+    let watcher = app.state::<DeviceWatcher>();
+    loop {
+        let event = tokio::select! {
+            event = events.next() => event,
+            _ = watcher.reconnect.notified() => {
+                log::info!("device_watcher: reconnecting to usbmuxd");
+                return Ok(());
+            }
+        };
+        let Some(event) = event else {
+            break;
+        };
+        match event? {
+            UsbmuxdListenEvent::Connected(dev) => on_attached(app, dev),
+            UsbmuxdListenEvent::Disconnected(device_id) => {
+                if let Some(change) = app.state::<DeviceWatcher>().detach(device_id) {
+                    log::info!("device_watcher: detached device id {}", device_id);
+                    emit(app, change);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn on_attached(app: &AppHandle, dev: UsbmuxdDevice) {
+    log::info!(
+        "device_watcher: attached {} ({:?})",
+        dev.udid,
+        dev.connection_type
+    );
+    let change = app.state::<DeviceWatcher>().attach(dev.clone());
+    let is_new = matches!(change, Change::Attached(_));
+    emit(app, change);
+    if !is_new {
+        return;
+    }
+
+    spawn_query(app, dev);
+}
+
+/// Re-read the descriptor for `udid`, e.g. after its trust state changed.
+pub fn refresh(app: &AppHandle, udid: &str) {
+    if let Some(dev) = app.state::<DeviceWatcher>().route(udid) {
+        spawn_query(app, dev);
+    }
+}
+
+fn spawn_query(app: &AppHandle, dev: UsbmuxdDevice) {
+    // Lockdown can take a while (or hang until the device is unlocked), so the
+    // descriptor lookup must not hold up the listen loop.
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        let device = device_info::query_device_info(&dev).await;
+        if let Some(device) = app.state::<DeviceWatcher>().update(device) {
+            emit(&app, Change::Updated(device));
+        }
+    });
+}
@@ -0,0 +1,219 @@
+//! The error every command returns to the frontend.
+//!
+//! Device operations fail with `IdeviceError`, whose Debug output means little
+//! to the user. Commands convert it into an `AppError`, and map the string
+//! errors of the file-backed modules where they call them, which the frontend receives as
+//! `{ code, message, remediation }`: `code` is stable and safe to switch on,
+//! `message` is for display and `remediation` says what to do about it, if
+//! there is anything.
+
+use std::fmt;
+
+use idevice::{remote_pairing::errors::RemotePairingError, IdeviceError};
+use serde::{Serialize, Serializer};
+
+#[derive(Debug, Clone)]
+pub enum AppError {
+    /// usbmuxd doesn't list the device over a usable route.
+    DeviceNotFound,
+    /// The device hasn't trusted this computer, or declined to.
+    NotTrusted,
+    Locked,
+    DeveloperModeOff,
+    ImageNotMounted,
+    /// The companion app isn't installed.
+    AppMissing,
+    /// CoreDeviceProxy, RSD or a service behind them couldn't be reached.
+    TunnelFailed(String),
+    /// The device refused remote pairing or pair verify.
+    PairingRejected(String),
+    Cancelled,
+    /// Pairing files are encrypted and the key isn't at hand.
+    StoreLocked,
+    /// The OS secret store wouldn't keep or hand out the pairing store key.
+    SecretStore(String),
+    /// The device didn't get to the expected state in time.
+    Timeout(String),
+    /// The device kept a setting a profile enforces.
+    ManagedSetting(String),
+    Usbmuxd(String),
+    /// The connection to the device broke.
+    Connection(String),
+    /// Reading or writing local files failed.
+    Io(String),
+    InvalidInput(String),
+    Other(String),
+}
+
+impl AppError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::DeviceNotFound => "deviceNotFound",
+            Self::NotTrusted => "notTrusted",
+            Self::Locked => "locked",
+            Self::DeveloperModeOff => "developerModeOff",
+            Self::ImageNotMounted => "imageNotMounted",
+            Self::AppMissing => "appMissing",
+            Self::TunnelFailed(_) => "tunnelFailed",
+            Self::PairingRejected(_) => "pairingRejected",
+            Self::Cancelled => "cancelled",
+            Self::StoreLocked => "storeLocked",
+            Self::SecretStore(_) => "secretStore",
+            Self::Timeout(_) => "timeout",
+            Self::ManagedSetting(_) => "managedSetting",
+            Self::Usbmuxd(_) => "usbmuxd",
+            Self::Connection(_) => "connection",
+            Self::Io(_) => "io",
+            Self::InvalidInput(_) => "invalidInput",
+            Self::Other(_) => "other",
+        }
+    }
+
+    pub fn remediation(&self) -> Option<&'static str> {
+        match self {
+            Self::DeviceNotFound => Some("Connect the device over USB and unlock it."),
+            Self::NotTrusted => {
+                Some("Unlock the device, tap Trust when it asks about this computer and try again.")
+            }
+            Self::Locked => Some("Unlock the device and keep it unlocked until setup is done."),
+            Self::DeveloperModeOff => {
+                Some("Turn on Developer Mode under Settings > Privacy & Security and try again.")
+            }
+            Self::ImageNotMounted => Some("Mount the developer disk image and try again."),
+            Self::AppMissing => Some("Install the Auto Capture app on the device first."),
+            Self::TunnelFailed(_) => Some("Reconnect the device over USB and try again."),
+            Self::PairingRejected(_) => Some(
+                "Accept the pairing on the device. If it keeps failing, remove this computer \
+                 under Settings > General > VPN & Device Management and set the device up again.",
+            ),
+            Self::StoreLocked => {
+                Some("Unlock the pairing store with its passphrase, or unlock the system keyring.")
+            }
+            Self::SecretStore(_) => Some(
+                "Unlock the system keyring, or set a passphrase to encrypt the pairing files \
+                 without it.",
+            ),
+            Self::Timeout(_) => Some(
+                "Keep the device unlocked and connected, follow the prompts on it and try again.",
+            ),
+            Self::ManagedSetting(_) => Some(
+                "Change the setting in the device's management profile, or leave it to the \
+                 profile when setting up.",
+            ),
+            Self::Usbmuxd(_) => Some(
+                "Make sure Apple Mobile Device Support (usbmuxd on Linux) is running and the \
+                 usbmuxd address in settings is right.",
+            ),
+            Self::Connection(_) => Some("Check the cable or Wi-Fi connection and try again."),
+            Self::Cancelled | Self::Io(_) | Self::InvalidInput(_) | Self::Other(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DeviceNotFound => write!(f, "The device is not connected."),
+            Self::NotTrusted => write!(f, "The device does not trust this computer."),
+            Self::Locked => write!(f, "The device is locked."),
+            Self::DeveloperModeOff => write!(f, "Developer Mode is off."),
+            Self::ImageNotMounted => write!(f, "The developer disk image is not mounted."),
+            Self::AppMissing => write!(f, "Auto Capture is not installed on the device."),
+            Self::TunnelFailed(detail) => {
+                write!(f, "Could not open a tunnel to the device: {}", detail)
+            }
+            Self::PairingRejected(detail) => write!(f, "The device rejected pairing: {}", detail),
+            Self::Cancelled => write!(f, "Cancelled."),
+            Self::StoreLocked => write!(f, "The stored pairing files are locked."),
+            Self::SecretStore(detail) => {
+                write!(f, "The system keyring is unavailable: {}", detail)
+            }
+            Self::Timeout(detail) => write!(f, "Timed out {}.", detail),
+            Self::ManagedSetting(detail) => write!(f, "{}", detail),
+            Self::Usbmuxd(detail) => write!(f, "Could not talk to usbmuxd: {}", detail),
+            Self::Connection(detail) => {
+                write!(f, "Lost the connection to the device: {}", detail)
+            }
+            Self::Io(detail) | Self::InvalidInput(detail) | Self::Other(detail) => {
+                write!(f, "{}", detail)
+            }
+        }
+    }
+}
+
+impl Serialize for AppError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Wire<'a> {
+            code: &'a str,
+            message: String,
+            remediation: Option<&'a str>,
+        }
+        Wire {
+            code: self.code(),
+            message: self.to_string(),
+            remediation: self.remediation(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl From<IdeviceError> for AppError {
+    fn from(e: IdeviceError) -> Self {
+        log::debug!("AppError: from {:?}", e);
+        match e {
+            IdeviceError::DeviceNotFound => Self::DeviceNotFound,
+            ref e if is_missing_pair_record(e) => Self::NotTrusted,
+            IdeviceError::InvalidHostID
+            | IdeviceError::SessionInactive
+            | IdeviceError::UserDeniedPairing
+            | IdeviceError::PairingDialogResponsePending => Self::NotTrusted,
+            IdeviceError::DeviceLocked | IdeviceError::PasswordProtected => Self::Locked,
+            IdeviceError::DeveloperModeNotEnabled => Self::DeveloperModeOff,
+            IdeviceError::ImageNotMounted => Self::ImageNotMounted,
+            // what house_arrest answers for a bundle id that isn't installed
+            IdeviceError::UnknownErrorType(ref kind)
+                if kind.contains("ApplicationLookupFailed") =>
+            {
+                Self::AppMissing
+            }
+            IdeviceError::CanceledByUser => Self::Cancelled,
+            IdeviceError::RemotePairing(
+                ref rp @ (RemotePairingError::PairVerifyFailed
+                | RemotePairingError::PairingRejected(_)
+                | RemotePairingError::SrpAuthFailed),
+            ) => Self::PairingRejected(rp.to_string()),
+            IdeviceError::RemotePairing(_)
+            | IdeviceError::CdTunnel(_)
+            | IdeviceError::Xpc(_)
+            | IdeviceError::ServiceNotFound
+            | IdeviceError::NoEstablishedConnection => Self::TunnelFailed(e.to_string()),
+            IdeviceError::Usbmuxd(_) => Self::Usbmuxd(e.to_string()),
+            IdeviceError::Socket(ref io) => Self::Connection(io.to_string()),
+            // carries our own messages; its Display drops them
+            IdeviceError::InternalError(message) => Self::Other(message),
+            _ => Self::Other(e.to_string()),
+        }
+    }
+}
+
+/// What usbmuxd's pair record lookup fails with when the device never trusted
+/// this computer. Every usbmuxd provider connect that needs a session reads it.
+fn is_missing_pair_record(e: &IdeviceError) -> bool {
+    matches!(e, IdeviceError::UnexpectedResponse(m) if m.contains("PairRecordData"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_pair_record_is_not_trusted() {
+        let e = IdeviceError::UnexpectedResponse(
+            "missing PairRecordData in pair record response".into(),
+        );
+        assert_eq!(AppError::from(e).code(), "notTrusted");
+        let e = IdeviceError::UnexpectedResponse("missing ProductVersion".into());
+        assert_eq!(AppError::from(e).code(), "other");
+    }
+}
@@ -0,0 +1,182 @@
+//! The identity this installation pairs under.
+//!
+//! A device keys remote pairing hosts by identifier, which is derived from the
+//! host name. Keeping one host name per installation means pairing again
+//! replaces our entry on the device instead of adding another one. The keys
+//! are stored alongside and updated after every pairing, since a full pair
+//! exchange replaces them. They are the same keys the pairing files carry, so
+//! they go through `vault` too.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use idevice::remote_pairing::RpPairingFile;
+use serde::{Deserialize, Serialize};
+
+use crate::{host_name::HostNameTemplate, vault};
+
+const IDENTITY_FILE: &str = "host_identity.json";
+const KEYS_FILE: &str = "host_identity.plist";
+
+// Serializes load-or-create and saves so concurrent setups agree on one identity
+static LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IdentityFile {
+    hostname: String,
+    /// Unix seconds.
+    created_at: u64,
+}
+
+pub struct HostIdentity {
+    /// Shown on the device under Settings > General > VPN & Device Management.
+    pub hostname: String,
+    pub created_at: u64,
+    pub pairing_file: RpPairingFile,
+}
+
+/// What the frontend gets to see; the keys stay on disk.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HostIdentityInfo {
+    pub hostname: String,
+    pub identifier: String,
+    pub created_at: u64,
+}
+
+impl HostIdentity {
+    fn generate() -> Self {
+        let hostname = pairing_hostname();
+        Self {
+            pairing_file: RpPairingFile::generate(&hostname),
+            hostname,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn info(&self) -> HostIdentityInfo {
+        HostIdentityInfo {
+            hostname: self.hostname.clone(),
+            identifier: self.pairing_file.identifier().to_string(),
+            created_at: self.created_at,
+        }
+    }
+}
+
+// Named after the template in the settings; one that stopped rendering, e.g.
+// because the operator was cleared, falls back to the default
+fn pairing_hostname() -> String {
+    let template = crate::settings::load().host_name;
+    template.render().unwrap_or_else(|e| {
+        log::warn!("host_identity: using the default host name: {}", e);
+        HostNameTemplate::default()
+            .render()
+            .unwrap_or_else(|_| "Auto Capture Pairing".into())
+    })
+}
+
+fn data_dir() -> Result<PathBuf, String> {
+    Ok(PathBuf::from(crate::app_data_folder()?))
+}
+
+/// The stored identity, created on first use. One that can't be read is an
+/// error rather than replaced; only `rotate` starts over.
+pub fn load_or_create() -> Result<HostIdentity, String> {
+    let _guard = LOCK.lock().unwrap();
+    let dir = data_dir()?;
+    if !dir.join(IDENTITY_FILE).exists() {
+        let identity = HostIdentity::generate();
+        write(&dir, &identity)?;
+        log::info!("host_identity: created {}", identity.hostname);
+        return Ok(identity);
+    }
+    read(&dir).map_err(|e| {
+        format!(
+            "the host identity can't be read ({}); rotate it to start over",
+            e
+        )
+    })
+}
+
+/// The stored identity, without creating one if there is none yet.
+pub fn load() -> Result<Option<HostIdentity>, String> {
+    let _guard = LOCK.lock().unwrap();
+    let dir = data_dir()?;
+    if !dir.join(IDENTITY_FILE).exists() {
+        return Ok(None);
+    }
+    read(&dir).map(Some)
+}
+
+/// Persist the keys pairing left in `identity`, a copy taken with
+/// `load_or_create` before pairing. Pairings run side by side, so the last one
+/// to finish wins; the others' keys stay in their pairing store entries. Skipped,
+/// returning `false`, when the identity was rotated meanwhile.
+pub fn save_keys(identity: &HostIdentity) -> Result<bool, String> {
+    let _guard = LOCK.lock().unwrap();
+    let dir = data_dir()?;
+    if read(&dir)?.hostname != identity.hostname {
+        return Ok(false);
+    }
+    write(&dir, identity)?;
+    Ok(true)
+}
+
+/// Throw the current identity away and start over with a new host name and keys.
+/// Devices paired under the old one keep it listed until it is removed there.
+pub fn rotate() -> Result<HostIdentity, String> {
+    let _guard = LOCK.lock().unwrap();
+    let identity = HostIdentity::generate();
+    write(&data_dir()?, &identity)?;
+    log::info!("host_identity: rotated to {}", identity.hostname);
+    Ok(identity)
+}
+
+/// Rewrite the stored keys through `rewrite`, like
+/// `pairing_store::rewrite_pairing_files`. Does nothing without an identity.
+pub fn rewrite_keys(rewrite: impl Fn(Vec<u8>) -> Result<Vec<u8>, String>) -> Result<(), String> {
+    let _guard = LOCK.lock().unwrap();
+    let path = data_dir()?.join(KEYS_FILE);
+    let bytes = match std::fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("failed to read {}: {}", path.display(), e)),
+    };
+    crate::replace_file(&path, &rewrite(bytes)?)
+}
+
+fn read(dir: &Path) -> Result<HostIdentity, String> {
+    let meta: IdentityFile =
+        serde_json::from_slice(&std::fs::read(dir.join(IDENTITY_FILE)).map_err(|e| e.to_string())?)
+            .map_err(|e| e.to_string())?;
+    let keys = vault::open(std::fs::read(dir.join(KEYS_FILE)).map_err(|e| e.to_string())?)?;
+    let pairing_file = RpPairingFile::from_bytes(&keys).map_err(|e| format!("{:?}", e))?;
+    Ok(HostIdentity {
+        hostname: meta.hostname,
+        created_at: meta.created_at,
+        pairing_file,
+    })
+}
+
+fn write(dir: &Path, identity: &HostIdentity) -> Result<(), String> {
+    std::fs::create_dir_all(dir).map_err(|e| format!("failed to create dir: {}", e))?;
+    let meta = IdentityFile {
+        hostname: identity.hostname.clone(),
+        created_at: identity.created_at,
+    };
+    let json = serde_json::to_vec_pretty(&meta)
+        .map_err(|e| format!("failed to serialize host identity: {}", e))?;
+    crate::replace_file(
+        &dir.join(KEYS_FILE),
+        &vault::seal(identity.pairing_file.to_bytes())?,
+    )?;
+    std::fs::write(dir.join(IDENTITY_FILE), json)
+        .map_err(|e| format!("failed to write host identity: {}", e))
+}
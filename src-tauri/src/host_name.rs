@@ -0,0 +1,260 @@
+//! The host name devices list this installation under.
+//!
+//! It appears under Settings > General > VPN & Device Management, so sites
+//! with many workstations name it after the computer, the operator or the
+//! site through a template. Placeholders:
+//!
+//! - `{computer}`: this computer's name,
+//! - `{operator}` and `{site}`: the values from the settings,
+//! - `{suffix}`: six random hex characters.
+//!
+//! The device keys pairings by an identifier derived from the host name, so
+//! the template must contain `{computer}` or `{suffix}`; otherwise two
+//! workstations would replace each other's pairing.
+
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_TEMPLATE: &str = "Auto Capture Pairing-{suffix}";
+
+/// Longer names are cut off on the device.
+const MAX_LEN: usize = 63;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct HostNameTemplate {
+    /// `None` uses `DEFAULT_TEMPLATE`.
+    pub template: Option<String>,
+    pub operator: Option<String>,
+    pub site: Option<String>,
+}
+
+impl HostNameTemplate {
+    /// Fill the template in and check the result is a name the device takes.
+    pub fn render(&self) -> Result<String, String> {
+        self.render_with(computer_name, suffix)
+    }
+
+    /// `render` with the placeholders that depend on this computer or on
+    /// chance filled in by `computer` and `suffix`.
+    fn render_with(
+        &self,
+        computer: impl Fn() -> Option<String>,
+        suffix: impl Fn() -> String,
+    ) -> Result<String, String> {
+        let template = self.template.as_deref().unwrap_or(DEFAULT_TEMPLATE);
+        if !template.contains("{computer}") && !template.contains("{suffix}") {
+            return Err(
+                "the host name template must contain {computer} or {suffix} so every \
+                 computer pairs under its own name"
+                    .into(),
+            );
+        }
+
+        let mut name = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            name.push_str(&rest[..start]);
+            let end = rest[start..]
+                .find('}')
+                .map(|end| start + end)
+                .ok_or("unclosed '{' in the host name template")?;
+            let value = match &rest[start + 1..end] {
+                "computer" => computer().ok_or("could not determine the computer name")?,
+                "operator" => field(&self.operator, "operator")?,
+                "site" => field(&self.site, "site")?,
+                "suffix" => suffix(),
+                other => return Err(format!("unknown placeholder {{{}}}", other)),
+            };
+            name.push_str(&value);
+            rest = &rest[end + 1..];
+        }
+        name.push_str(rest);
+
+        let name = name.trim().to_string();
+        if name.is_empty() {
+            return Err("the host name is empty".into());
+        }
+        if name.chars().count() > MAX_LEN {
+            return Err(format!(
+                "\"{}\" is longer than {} characters",
+                name, MAX_LEN
+            ));
+        }
+        if let Some(c) = name.chars().find(|c| !allowed(*c)) {
+            return Err(format!(
+                "\"{}\" contains '{}'; use letters, digits, spaces and - _ . ' ( )",
+                name, c
+            ));
+        }
+        Ok(name)
+    }
+}
+
+fn allowed(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, ' ' | '-' | '_' | '.' | '\'' | '(' | ')')
+}
+
+fn field(value: &Option<String>, what: &str) -> Result<String, String> {
+    value
+        .as_deref()
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| {
+            format!(
+                "the host name template uses {{{}}} but no {} is set",
+                what, what
+            )
+        })
+}
+
+fn suffix() -> String {
+    uuid::Uuid::new_v4()
+        .simple()
+        .to_string()
+        .chars()
+        .take(6)
+        .collect()
+}
+
+/// The computer's name without any domain, with characters the device won't
+/// take replaced.
+fn computer_name() -> Option<String> {
+    #[cfg(target_os = "windows")]
+    let name = std::env::var("COMPUTERNAME").ok()?;
+
+    #[cfg(not(target_os = "windows"))]
+    let name = {
+        let output = std::process::Command::new("hostname").output().ok()?;
+        String::from_utf8(output.stdout).ok()?
+    };
+
+    let name: String = name
+        .trim()
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .map(|c| if allowed(c) { c } else { '-' })
+        .collect();
+    (!name.is_empty()).then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(
+        template: &str,
+        operator: Option<&str>,
+        site: Option<&str>,
+    ) -> Result<String, String> {
+        HostNameTemplate {
+            template: Some(template.to_string()),
+            operator: operator.map(str::to_string),
+            site: site.map(str::to_string),
+        }
+        .render_with(|| Some("LAB-PC-07".into()), || "a1b2c3".into())
+    }
+
+    #[test]
+    fn accepted_templates() {
+        let cases = [
+            ("{computer}", None, None, "LAB-PC-07"),
+            ("{suffix}", None, None, "a1b2c3"),
+            (
+                "Auto Capture Pairing-{suffix}",
+                None,
+                None,
+                "Auto Capture Pairing-a1b2c3",
+            ),
+            (
+                "{site} {computer}",
+                None,
+                Some("Ward 4"),
+                "Ward 4 LAB-PC-07",
+            ),
+            (
+                "{operator}'s ({computer})",
+                Some("Sam"),
+                None,
+                "Sam's (LAB-PC-07)",
+            ),
+            ("{computer}{computer}", None, None, "LAB-PC-07LAB-PC-07"),
+            ("  {computer}  ", None, None, "LAB-PC-07"),
+            ("{operator}-{suffix}", Some("  Sam  "), None, "Sam-a1b2c3"),
+            ("{site}_{suffix}.v2", None, Some("B"), "B_a1b2c3.v2"),
+        ];
+        for (template, operator, site, expected) in cases {
+            assert_eq!(
+                render(template, operator, site).as_deref(),
+                Ok(expected),
+                "{:?}",
+                template
+            );
+        }
+        // exactly at the limit
+        let long = format!("{}{{suffix}}", "x".repeat(MAX_LEN - 6));
+        assert_eq!(render(&long, None, None).map(|n| n.len()), Ok(MAX_LEN));
+    }
+
+    #[test]
+    fn rejected_templates() {
+        let too_long = format!("{}{{suffix}}", "x".repeat(MAX_LEN - 5));
+        let cases = [
+            ("Auto Capture Pairing", None, None, "must contain"),
+            ("{operator}", Some("Sam"), None, "must contain"),
+            ("", None, None, "must contain"),
+            ("{suffix}-{computer", None, None, "unclosed"),
+            ("{suffix}-{", None, None, "unclosed"),
+            ("{suffix}-{user}", None, None, "unknown placeholder {user}"),
+            ("{suffix}-{}", None, None, "unknown placeholder {}"),
+            ("{operator}-{suffix}", None, None, "no operator is set"),
+            (
+                "{operator}-{suffix}",
+                Some("   "),
+                None,
+                "no operator is set",
+            ),
+            ("{site}-{suffix}", None, None, "no site is set"),
+            (too_long.as_str(), None, None, "longer than"),
+            ("{suffix}/{computer}", None, None, "contains '/'"),
+            ("{operator} {suffix}", Some("Zoë"), None, "contains 'ë'"),
+            ("{suffix}\tx", None, None, "contains '\t'"),
+        ];
+        for (template, operator, site, expected) in cases {
+            let error = render(template, operator, site).expect_err(template);
+            assert!(
+                error.contains(expected),
+                "{:?} failed with {:?}",
+                template,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn placeholders_that_cannot_be_filled() {
+        let template = HostNameTemplate {
+            template: Some("{computer}".into()),
+            ..Default::default()
+        };
+        assert!(template.render_with(|| None, suffix).is_err());
+        // an empty suffix leaves nothing after trimming
+        let template = HostNameTemplate {
+            template: Some(" {suffix} ".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            template.render_with(|| None, String::new),
+            Err("the host name is empty".into())
+        );
+    }
+
+    #[test]
+    fn default_template() {
+        let name = HostNameTemplate::default().render().unwrap();
+        assert!(name.starts_with("Auto Capture Pairing-"), "{}", name);
+        assert_eq!(name.len(), "Auto Capture Pairing-".len() + 6);
+    }
+}
@@ -1,86 +1,58 @@
-use std::collections::HashMap;
+use futures::{stream::FuturesUnordered, StreamExt};
+use idevice::{amfi::AmfiClient, usbmuxd::UsbmuxdDevice, IdeviceError, IdeviceService};
 
-use idevice::{
-    amfi::AmfiClient,
-    lockdown::LockdownClient,
-    usbmuxd::{Connection, UsbmuxdAddr, UsbmuxdConnection},
-    IdeviceError, IdeviceService,
+use crate::{
+    device_info::{self, DeviceInfo},
+    locator::{self, DeviceLocator},
+    muxer,
 };
 
-/// Query usbmuxd for attached USB devices and try to read each device's
-/// `DeviceName` from lockdown. Returns a map of `DeviceName -> UsbmuxdDevice`.
+/// Query usbmuxd for attached devices (USB and network) and read each
+/// device's descriptor from lockdown. A device visible over both routes is
+/// reported once. Devices are queried concurrently, each with its own
+/// deadline, and `on_device` is called as each descriptor arrives. Devices
+/// lockdown refuses (or that don't answer in time) are still returned, with
+/// `DeviceInfo::error` set.
 ///
 /// This is an async helper you can call from your tokio runtime:
-/// let devices = idevice_helpers::get_devices().await?;
-pub async fn get_devices() -> Result<HashMap<String, String>, IdeviceError> {
+/// let devices = idevice_helpers::get_devices(|_| {}).await?;
+pub async fn get_devices(on_device: impl Fn(&DeviceInfo)) -> Result<Vec<DeviceInfo>, IdeviceError> {
     // Connect to usbmuxd
-    let mut uc = UsbmuxdConnection::default().await?;
+    let mut uc = muxer::connect().await?;
     let devs = uc.get_devices().await?;
 
-    let mut selections = HashMap::new();
+    let mut udids: Vec<&str> = devs.iter().map(|d| d.udid.as_str()).collect();
+    udids.sort();
+    udids.dedup();
 
-    for dev in devs
+    let mut pending: FuturesUnordered<_> = udids
         .into_iter()
-        .filter(|x| x.connection_type == Connection::Usb)
-    {
-        // Create a provider for lockdown
-        let provider = dev.to_provider(UsbmuxdAddr::default(), "idevice_pair");
-
-        // Try to connect to lockdown; if it fails log a warning and skip the device.
-        match LockdownClient::connect(&provider).await {
-            Ok(mut lc) => {
-                match lc.get_value(None, None).await {
-                    Ok(values) => {
-                        if let Some(name) = values
-                            .as_dictionary()
-                            .and_then(|d| d.get("DeviceName"))
-                            .and_then(|v| v.as_string())
-                        {
-                            // store device name -> udid so the map is easily serializable to JSON
-                            selections.insert(name.to_string(), dev.udid.clone());
-                        } else {
-                            // If DeviceName isn't present we skip; you might want to
-                            // insert devices by UDID or another key instead.
-                            log::warn!("Device {} had no DeviceName, skipping", dev.udid);
-                        }
-                    }
-                    Err(e) => {
-                        log::warn!("Failed to get lockdown values for {}: {e:?}", dev.udid);
-                    }
-                }
-            }
-            Err(e) => {
-                log::warn!("Failed to connect to lockdown for {}: {e:?}", dev.udid);
-            }
-        }
+        .filter_map(|udid| {
+            let routes: Vec<&UsbmuxdDevice> = devs.iter().filter(|d| d.udid == udid).collect();
+            let dev = locator::select_device(routes.iter().copied(), udid)?;
+            Some(async move {
+                let mut info = device_info::query_device_info(dev).await;
+                info.set_routes(routes);
+                info
+            })
+        })
+        .collect();
+
+    let mut devices = Vec::new();
+    while let Some(info) = pending.next().await {
+        on_device(&info);
+        devices.push(info);
     }
+    devices.sort_by(|a, b| a.udid.cmp(&b.udid));
 
-    Ok(selections)
+    Ok(devices)
 }
 
-// check dev mode
-pub async fn is_device_in_dev_mode(udid: &str) -> Result<bool, IdeviceError> {
-    log::info!("is_device_in_dev_mode: starting for udid={}", udid);
-
-    // Connect to usbmuxd
-    log::debug!("is_device_in_dev_mode: connecting to usbmuxd");
-    let mut uc: UsbmuxdConnection = UsbmuxdConnection::default().await?;
-    log::debug!("is_device_in_dev_mode: connected to usbmuxd");
-
-    // Get device list and find by UDID
-    let devices = uc.get_devices().await?;
-    log::debug!("is_device_in_dev_mode: found {} devices", devices.len());
-    let dev = match devices.into_iter().find(|d| d.udid == udid) {
-        Some(d) => d,
-        None => return Err(IdeviceError::DeviceNotFound),
-    };
-    log::info!("is_device_in_dev_mode: selected device {}", dev.udid);
-
-    // Build a provider for lockdown and connect
-    let provider = dev.to_provider(UsbmuxdAddr::default(), "idevice_pair");
+/// Whether developer mode is enabled on the device.
+pub async fn is_device_in_dev_mode(device: &DeviceLocator) -> Result<bool, IdeviceError> {
+    log::info!("is_device_in_dev_mode: starting for udid={}", device.udid());
 
-    // Try to connect to lockdown; if it fails log a warning and skip the device.
-    let mut amfi_client = AmfiClient::connect(&provider).await?;
+    let mut amfi_client = AmfiClient::connect(device.provider()).await?;
 
     // Check DevelopmentMode status returns true if enabled, false if disabled
     let dev_mode_value = amfi_client.get_developer_mode_status().await?;
@@ -88,31 +60,11 @@ pub async fn is_device_in_dev_mode(udid: &str) -> Result<bool, IdeviceError> {
     Ok(dev_mode_value)
 }
 
-// Reveal dev mode
-pub async fn reveal_dev_mode(udid: &str) -> Result<(), IdeviceError> {
-    log::info!("reveal_dev_mode: starting for udid={}", udid);
+/// Make the Developer Mode toggle appear under Settings > Privacy & Security.
+pub async fn reveal_dev_mode(device: &DeviceLocator) -> Result<(), IdeviceError> {
+    log::info!("reveal_dev_mode: starting for udid={}", device.udid());
 
-    // Connect to usbmuxd
-    log::debug!("is_device_in_dev_mode: connecting to usbmuxd");
-    let mut uc: UsbmuxdConnection = UsbmuxdConnection::default().await?;
-    log::debug!("is_device_in_dev_mode: connected to usbmuxd");
-
-    // Get device list and find by UDID
-    let devices = uc.get_devices().await?;
-    log::debug!("is_device_in_dev_mode: found {} devices", devices.len());
-    let dev = match devices.into_iter().find(|d| d.udid == udid) {
-        Some(d) => d,
-        None => return Err(IdeviceError::DeviceNotFound),
-    };
-    log::info!("is_device_in_dev_mode: selected device {}", dev.udid);
-
-    // Build a provider for lockdown and connect
-    let provider = dev.to_provider(UsbmuxdAddr::default(), "idevice_pair");
-
-    // Try to connect to lockdown; if it fails log a warning and skip the device.
-    let mut amfi_client = AmfiClient::connect(&provider).await?;
-
-    // Check DevelopmentMode status returns true if enabled, false if disabled
+    let mut amfi_client = AmfiClient::connect(device.provider()).await?;
     amfi_client.reveal_developer_mode_option_in_ui().await?;
     Ok(())
 }
@@ -132,7 +84,7 @@ pub fn check_apple_drivers() -> Result<String, String> {
         .map_err(|e| format!("failed to run pnputil: {}", e))?;
     let stdout = String::from_utf8_lossy(&output.stdout).to_lowercase();
 
-    if (stdout.contains("netaapl64") || stdout.contains("usbaapl64")) {
+    if stdout.contains("netaapl64") || stdout.contains("usbaapl64") {
         Ok("Installed".into())
     } else {
         Ok("Missing".into())
@@ -0,0 +1,132 @@
+//! Sideloading the companion app from a local IPA.
+//!
+//! The IPA is copied over AFC into `PublicStaging`, where installation_proxy
+//! picks it up, and removed again once the install finished or failed.
+
+use std::path::Path;
+
+use idevice::{
+    afc::{errors::AfcError, opcode::AfcFopenMode, AfcClient},
+    installation_proxy::InstallationProxyClient,
+    IdeviceError, IdeviceService,
+};
+use serde::Serialize;
+
+use crate::{error::AppError, locator::DeviceLocator};
+
+/// Event emitted while an IPA is uploaded and installed.
+pub const INSTALL_PROGRESS_EVENT: &str = "install-progress";
+
+/// installation_proxy only installs packages from this AFC directory.
+const STAGING_DIR: &str = "/PublicStaging";
+
+/// Size of each AFC write, and so the granularity of upload progress.
+const UPLOAD_CHUNK: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum InstallStage {
+    Uploading,
+    Installing,
+    Installed,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstallProgress {
+    pub stage: InstallStage,
+    /// 0-100 within the current stage.
+    pub percent: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstallProgressEvent {
+    pub udid: String,
+    #[serde(flatten)]
+    pub progress: InstallProgress,
+}
+
+/// Push the IPA at `ipa_path` to `device` and install it, upgrading the app
+/// in place if it is already installed.
+pub async fn install_app(
+    device: &DeviceLocator,
+    ipa_path: &Path,
+    on_progress: impl Fn(InstallProgress),
+) -> Result<(), AppError> {
+    let udid = device.udid();
+    log::info!(
+        "install_app: installing {} on udid={}",
+        ipa_path.display(),
+        udid
+    );
+
+    let ipa = std::fs::read(ipa_path)
+        .map_err(|e| AppError::Io(format!("failed to read {}: {}", ipa_path.display(), e)))?;
+    let file_name = ipa_path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "app.ipa".into());
+    let staged_path = format!("{}/{}", STAGING_DIR, file_name);
+
+    let mut afc = AfcClient::connect(device.provider()).await?;
+    match afc.mk_dir(STAGING_DIR).await {
+        Ok(()) | Err(IdeviceError::Afc(AfcError::ObjectExists)) => {}
+        Err(e) => return Err(e.into()),
+    }
+    upload(&mut afc, &staged_path, &ipa, &on_progress).await?;
+    log::debug!("install_app: staged {} ({} bytes)", staged_path, ipa.len());
+
+    let result = install(device, &staged_path, &on_progress).await;
+
+    // Don't leave the package behind whether or not it installed
+    if let Err(e) = afc.remove(&staged_path).await {
+        log::warn!("install_app: failed to remove {}: {:?}", staged_path, e);
+    }
+    result?;
+
+    log::info!("install_app: installed {} on {}", file_name, udid);
+    on_progress(InstallProgress {
+        stage: InstallStage::Installed,
+        percent: 100,
+    });
+    Ok(())
+}
+
+async fn upload(
+    afc: &mut AfcClient,
+    path: &str,
+    bytes: &[u8],
+    on_progress: &impl Fn(InstallProgress),
+) -> Result<(), IdeviceError> {
+    let mut file = afc.open(path, AfcFopenMode::WrOnly).await?;
+    let mut sent = 0;
+    for chunk in bytes.chunks(UPLOAD_CHUNK) {
+        file.write_entire(chunk).await?;
+        sent += chunk.len();
+        on_progress(InstallProgress {
+            stage: InstallStage::Uploading,
+            percent: (sent * 100 / bytes.len().max(1)) as u64,
+        });
+    }
+    file.close().await
+}
+
+async fn install(
+    device: &DeviceLocator,
+    staged_path: &str,
+    on_progress: &impl Fn(InstallProgress),
+) -> Result<(), IdeviceError> {
+    let mut ip = InstallationProxyClient::connect(device.provider()).await?;
+    let callback = |(percent, ()): (u64, ())| {
+        on_progress(InstallProgress {
+            stage: InstallStage::Installing,
+            percent,
+        });
+        async {}
+    };
+    // Upgrade installs the app when it is missing and keeps its data when it
+    // isn't, whichever app the IPA turns out to hold
+    ip.upgrade_with_callback(staged_path, None, callback, ())
+        .await
+}
@@ -1,5 +1,34 @@
+mod batch;
+mod companion;
+mod ddi;
+mod developer_mode;
+mod device_info;
+mod device_watcher;
+mod error;
+mod host_identity;
+mod host_name;
 mod idevice_helpers;
+mod installer;
+mod locator;
+mod muxer;
+mod operations;
 mod pairing;
+mod pairing_store;
+mod pairing_verify;
+mod pin_prompt;
+mod preflight;
+mod settings;
+mod setup_progress;
+mod trust;
+mod vault;
+mod wifi_debugging;
+
+use error::AppError;
+use locator::{DeviceLocator, Route};
+use tauri::{Emitter, Manager};
+
+/// Emitted by `get_devices` for each device as its descriptor arrives.
+const DEVICE_ENUMERATED_EVENT: &str = "device-enumerated";
 
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 /*#[tauri::command]
@@ -8,39 +37,197 @@ fn greet(name: &str) -> String {
 }*/
 
 #[tauri::command]
-async fn get_devices() -> Result<std::collections::HashMap<String, String>, String> {
-    // Retrieve a serializable map from the helper (DeviceName -> UDID)
-    let map = idevice_helpers::get_devices()
+async fn get_devices(app: tauri::AppHandle) -> Result<Vec<device_info::DeviceInfo>, AppError> {
+    // One descriptor per attached device, keyed by UDID. Each one is also
+    // emitted as soon as it is ready so slow devices don't hold up the rest.
+    let devices = idevice_helpers::get_devices(|device| {
+        let _ = app.emit(DEVICE_ENUMERATED_EVENT, device);
+    })
+    .await?;
+
+    // Tauri will serialize the list to JSON for the frontend
+    Ok(devices)
+}
+
+// Snapshot of the live device table; changes arrive as device-* events afterwards
+#[tauri::command]
+fn subscribe_devices(
+    app: tauri::AppHandle,
+    watcher: tauri::State<'_, device_watcher::DeviceWatcher>,
+) -> Vec<device_info::DeviceInfo> {
+    device_watcher::start(app);
+    watcher.snapshot()
+}
+
+// Resolves the UDID once; the locator is then handed to every operation the command runs
+async fn locate(udid: &str, route: Route, op: &'static str) -> Result<DeviceLocator, AppError> {
+    DeviceLocator::locate(udid, route, op)
+        .await
+        .map_err(AppError::from)
+}
+
+// Runs the trust flow if needed, relaying what the user must do as trust-prompt events
+async fn ensure_trusted(app: &tauri::AppHandle, device: &DeviceLocator) -> Result<(), AppError> {
+    let udid = device.udid();
+    trust::ensure_trusted(device, trust::TRUST_TIMEOUT, |prompt| {
+        let _ = app.emit(
+            trust::TRUST_PROMPT_EVENT,
+            trust::TrustPromptEvent {
+                udid: udid.to_string(),
+                prompt,
+            },
+        );
+        if prompt == trust::TrustPrompt::Trusted {
+            device_watcher::refresh(app, udid);
+        }
+    })
+    .await?;
+    Ok(())
+}
+
+// Stored pairing files and host keys can't be read or written while encrypted and locked
+fn ensure_store_unlocked() -> Result<(), AppError> {
+    if vault::is_locked() {
+        return Err(AppError::StoreLocked);
+    }
+    Ok(())
+}
+
+// Remote pairing under this installation's host identity, prompting for a code if the device shows one.
+// The result is kept in the pairing store.
+async fn pair_under_host_identity(
+    app: &tauri::AppHandle,
+    device: &DeviceLocator,
+    on_stage: &impl Fn(setup_progress::SetupStage),
+) -> Result<
+    (
+        idevice::remote_pairing::RpPairingFile,
+        pairing_store::PairingEntry,
+    ),
+    AppError,
+> {
+    let udid = device.udid();
+    ensure_store_unlocked()?;
+    // a copy, so devices in a batch pair side by side; only loading and saving take the lock
+    let mut identity = host_identity::load_or_create().map_err(AppError::Io)?;
+    let pairing_file = pairing::generate_pairing_file(
+        device,
+        &mut identity,
+        || pin_prompt::request(app, udid),
+        on_stage,
+    )
+    .await?;
+    // pairing may have replaced the identity's keys
+    if !host_identity::save_keys(&identity).map_err(AppError::Io)? {
+        log::info!(
+            "Host identity was rotated while pairing {}; the device keeps {}",
+            udid,
+            identity.hostname
+        );
+    }
+
+    let info = app.state::<device_watcher::DeviceWatcher>().device(udid);
+    let entry = pairing_store::add(
+        udid,
+        info.as_ref().and_then(|i| i.name.clone()),
+        info.as_ref().and_then(|i| i.product_version.clone()),
+        &identity.hostname,
+        &pairing_file,
+    )
+    .map_err(AppError::Io)?;
+    Ok((pairing_file, entry))
+}
+
+// Turns Wi-Fi debugging on before pairing unless the options leave it to MDM
+async fn prepare_wifi_debugging(
+    device: &DeviceLocator,
+    options: &setup_progress::SetupOptions,
+    on_stage: &impl Fn(setup_progress::SetupStage),
+) -> Result<(), AppError> {
+    if options.skip_wifi_debugging {
+        log::info!("Leaving Wi-Fi debugging on {} as it is", device.udid());
+        return Ok(());
+    }
+    on_stage(setup_progress::SetupStage::WifiDebugging);
+    wifi_debugging::set_enabled(device, true).await
+}
+
+// Uploads a stored pairing and records the outcome on its store entry
+async fn upload_stored_pairing(
+    device: &DeviceLocator,
+    entry: &pairing_store::PairingEntry,
+    pairing_file: &idevice::remote_pairing::RpPairingFile,
+    on_stage: &impl Fn(setup_progress::SetupStage),
+) -> Result<companion::CompanionStatus, AppError> {
+    let result = pairing::upload_pairing_file_to_device(device, pairing_file, on_stage)
         .await
-        .map_err(|e| format!("idevice error: {:?}", e))?;
+        .map_err(AppError::from);
+    let outcome = match &result {
+        Ok(status) if status.is_ready() => Ok(()),
+        Ok(status) => Err(format!("companion app not ready: {:?}", status)),
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = pairing_store::record_upload(&entry.id, outcome) {
+        log::warn!("Failed to record upload of {}: {}", &entry.id, e);
+    }
+    result
+}
 
-    // Return the map directly; Tauri will serialize it to JSON for the frontend
-    Ok(map)
+// pair_device (asks the user to trust this computer if it isn't already)
+#[tauri::command]
+async fn pair_device(
+    app: tauri::AppHandle,
+    udid: String,
+    on_start: tauri::ipc::Channel<operations::OperationStarted>,
+) -> Result<(), AppError> {
+    let on_start = operations::send_to(&on_start);
+    operations::run(&app, "pair_device", &udid, on_start, async {
+        log::info!("Pairing with device with UDID: {}", &udid);
+        let device = locate(&udid, Route::Usb, "pair_device").await?;
+        ensure_trusted(&app, &device).await?;
+        log::info!("Device {} trusts this computer", &udid);
+        Ok(())
+    })
+    .await
 }
 
+// Pairs and writes the pairing file to destination, or returns it without one. Either way it is the
+// plaintext file with private keys, even while the stored files are encrypted
 #[tauri::command]
 async fn generate_pairing_file(
+    app: tauri::AppHandle,
     udid: String,
     destination: Option<String>,
-) -> Result<String, String> {
-    // Call the async pairing helper and return a serialized result
-    let pairing_file = pairing::generate_pairing_file_for_udid(&udid)
-        .await
-        .map_err(|e| format!("idevice error: {:?}", e))?;
+    options: Option<setup_progress::SetupOptions>,
+    on_start: tauri::ipc::Channel<operations::OperationStarted>,
+) -> Result<String, AppError> {
+    let on_start = operations::send_to(&on_start);
+    operations::run(&app, "generate_pairing_file", &udid, on_start, async {
+        let device = locate(&udid, Route::Usb, "generate_pairing_file").await?;
+        ensure_trusted(&app, &device).await?;
+        prepare_wifi_debugging(&device, &options.unwrap_or_default(), &|_| {}).await?;
 
-    let pairing_file_plist: Vec<u8> = pairing_file.to_bytes();
+        // Call the async pairing helper and return a serialized result
+        let (pairing_file, _) = pair_under_host_identity(&app, &device, &|_| {}).await?;
 
-    if let Some(dest) = destination {
-        std::fs::write(&dest, &pairing_file_plist)
-            .map_err(|e| format!("failed to write pairing file to {}: {}", dest, e))?;
-        Ok(dest)
-    } else {
-        Ok(String::from_utf8(pairing_file_plist).map_err(|e| format!("invalid utf8: {}", e))?)
-    }
+        if let Some(dest) = destination {
+            pairing_store::write_plaintext(std::path::Path::new(&dest), &pairing_file)
+                .map_err(AppError::Io)?;
+            Ok(dest)
+        } else {
+            String::from_utf8(pairing_file.to_bytes())
+                .map_err(|e| AppError::Other(format!("invalid utf8: {}", e)))
+        }
+    })
+    .await
 }
 
 #[tauri::command]
-fn get_app_data_folder() -> Result<String, String> {
+fn get_app_data_folder() -> Result<String, AppError> {
+    app_data_folder().map_err(AppError::Io)
+}
+
+fn app_data_folder() -> Result<String, String> {
     // Return an application-specific data folder path for the current platform.
     // We avoid adding a new dependency by consulting common environment variables.
     let app_name = "Auto Capture Pair";
@@ -52,7 +239,7 @@ fn get_app_data_folder() -> Result<String, String> {
             path.push(app_name);
             return Ok(path.to_string_lossy().to_string());
         }
-        return Err("APPDATA not set".into());
+        Err("APPDATA not set".into())
     }
 
     #[cfg(not(target_os = "windows"))]
@@ -69,33 +256,248 @@ fn get_app_data_folder() -> Result<String, String> {
             path.push(app_name);
             return Ok(path.to_string_lossy().to_string());
         }
-        return Err("Could not determine data directory".into());
+        Err("Could not determine data directory".into())
     }
 }
 
+// Writes bytes to a temp file next to path and renames it over path, so a write that fails
+// partway leaves the old file (often the only copy of a private key) in place
+fn replace_file(path: &std::path::Path, bytes: &[u8]) -> Result<(), String> {
+    use std::io::Write;
+
+    let failed = |e: std::io::Error| format!("failed to write {}: {}", path.display(), e);
+    let file_name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let tmp = path.with_file_name(format!(".{}.tmp", file_name));
+    let written = std::fs::File::create(&tmp)
+        .and_then(|mut file| {
+            file.write_all(bytes)?;
+            file.sync_all()
+        })
+        .and_then(|()| std::fs::rename(&tmp, path));
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(failed(e));
+    }
+    Ok(())
+}
+
+// Readiness checklist for the device, so the UI can show what blocks setup
+#[tauri::command]
+async fn preflight(udid: String) -> Result<preflight::PreflightReport, AppError> {
+    let device = locate(&udid, Route::Any, "preflight").await?;
+    Ok(preflight::preflight(&device).await)
+}
+
 //setup_device(gens the pairing file and uploads it to the device)
+// Returns the companion app status; anything but ready means nothing was uploaded.
+// on_progress first gets the operation id to cancel with, then each stage as it starts.
 #[tauri::command]
-async fn setup_device(udid: String) -> Result<(), String> {
-    log::info!("Setting up device with UDID: {}", &udid);
-    let pairing_file = pairing::generate_pairing_file_for_udid(&udid)
-        .await
-        .map_err(|e| format!("idevice error: {:?}", e))?;
-    log::info!("Generated pairing file for device {}", &udid);
+async fn setup_device(
+    app: tauri::AppHandle,
+    udid: String,
+    options: Option<setup_progress::SetupOptions>,
+    on_progress: tauri::ipc::Channel<setup_progress::SetupEvent>,
+) -> Result<companion::CompanionStatus, AppError> {
+    let options = options.unwrap_or_default();
+    setup_one(
+        &app,
+        &udid,
+        &options,
+        |started| {
+            let _ = on_progress.send(setup_progress::SetupEvent::Started(started));
+        },
+        |progress| {
+            let _ = on_progress.send(setup_progress::SetupEvent::Progress(progress));
+        },
+    )
+    .await
+}
+
+// Sets several devices up, `options.concurrency` at a time, carrying on past failures.
+// Per-device progress and results arrive on on_event; the summary covers every device.
+#[tauri::command]
+async fn setup_devices(
+    app: tauri::AppHandle,
+    udids: Vec<String>,
+    options: Option<batch::BatchOptions>,
+    on_event: tauri::ipc::Channel<batch::BatchEvent>,
+) -> Result<batch::BatchSummary, AppError> {
+    use futures::StreamExt;
+
+    let options = options.unwrap_or_default();
+    let mut seen = std::collections::HashSet::new();
+    let udids: Vec<String> = udids
+        .into_iter()
+        .filter(|u| seen.insert(u.clone()))
+        .collect();
+    log::info!(
+        "Setting up {} devices, {} at a time",
+        udids.len(),
+        options.concurrency()
+    );
 
-    pairing::upload_pairing_file_to_device(&udid, &pairing_file)
+    let started = std::time::Instant::now();
+    let results: Vec<batch::DeviceResult> = futures::stream::iter(udids)
+        .map(|udid| {
+            let (app, options, on_event) = (&app, &options, &on_event);
+            async move {
+                let device_started = std::time::Instant::now();
+                let result = setup_one(
+                    app,
+                    &udid,
+                    &options.setup,
+                    |started| {
+                        let _ = on_event.send(batch::BatchEvent::Started(started));
+                    },
+                    |progress| {
+                        let _ = on_event.send(batch::BatchEvent::Progress(progress));
+                    },
+                )
+                .await;
+                let device = batch::DeviceResult::new(udid, result, device_started.elapsed());
+                let _ = on_event.send(batch::BatchEvent::Finished(device.clone()));
+                device
+            }
+        })
+        .buffered(options.concurrency())
+        .collect()
+        .await;
+
+    let summary = batch::BatchSummary::new(results, started.elapsed());
+    log::info!(
+        "Batch setup done: {} succeeded, {} not ready, {} failed, {} cancelled",
+        summary.succeeded,
+        summary.not_ready,
+        summary.failed,
+        summary.cancelled
+    );
+    Ok(summary)
+}
+
+// One device's setup as its own cancellable operation, logging where it stopped if it didn't finish
+async fn setup_one(
+    app: &tauri::AppHandle,
+    udid: &str,
+    options: &setup_progress::SetupOptions,
+    on_start: impl FnOnce(operations::OperationStarted),
+    on_progress: impl Fn(setup_progress::SetupProgress),
+) -> Result<companion::CompanionStatus, AppError> {
+    log::info!("Setting up device with UDID: {}", udid);
+    let reporter = setup_progress::SetupReporter::new(udid, on_progress);
+    let result = operations::run(
+        app,
+        "setup_device",
+        udid,
+        on_start,
+        run_setup(app, udid, options, &|stage| reporter.stage(stage)),
+    )
+    .await;
+    if let Err(e) = &result {
+        log::error!(
+            "setup_device: {} stopped during {:?} after {:?}: {}",
+            udid,
+            reporter.current(),
+            reporter.elapsed(),
+            e
+        );
+    }
+    result
+}
+
+async fn run_setup(
+    app: &tauri::AppHandle,
+    udid: &str,
+    options: &setup_progress::SetupOptions,
+    on_stage: &impl Fn(setup_progress::SetupStage),
+) -> Result<companion::CompanionStatus, AppError> {
+    use setup_progress::SetupStage;
+
+    // generating needs USB, and the same route works for the upload
+    let device = locate(udid, Route::Usb, "setup_device").await?;
+    on_stage(SetupStage::Trust);
+    ensure_trusted(app, &device).await?;
+
+    // no point pairing if the app can't receive the file
+    on_stage(SetupStage::CompanionCheck);
+    let status = companion::verify(&device).await?;
+    if !status.is_ready() {
+        log::warn!("Companion app on {} is not ready: {:?}", udid, status);
+        return Ok(status);
+    }
+
+    // the companion app reaches the device over Wi-Fi afterwards
+    prepare_wifi_debugging(&device, options, on_stage).await?;
+
+    let (pairing_file, entry) = pair_under_host_identity(app, &device, on_stage).await?;
+    log::info!("Generated pairing file {} for device {}", &entry.id, udid);
+
+    let status = upload_stored_pairing(&device, &entry, &pairing_file, on_stage).await?;
+    log::info!(
+        "Uploaded pairing file to device {} (companion {})",
+        &udid,
+        status.version().unwrap_or("unknown")
+    );
+    Ok(status)
+}
+
+// Sideloads the IPA at ipa_path; progress arrives as install-progress events
+#[tauri::command]
+async fn install_app(
+    app: tauri::AppHandle,
+    udid: String,
+    ipa_path: String,
+    on_start: tauri::ipc::Channel<operations::OperationStarted>,
+) -> Result<(), AppError> {
+    let on_start = operations::send_to(&on_start);
+    operations::run(&app, "install_app", &udid, on_start, async {
+        log::info!("Installing {} on device with UDID: {}", &ipa_path, &udid);
+        let device = locate(&udid, Route::Any, "install_app").await?;
+        installer::install_app(&device, std::path::Path::new(&ipa_path), |progress| {
+            let _ = app.emit(
+                installer::INSTALL_PROGRESS_EVENT,
+                installer::InstallProgressEvent {
+                    udid: udid.clone(),
+                    progress,
+                },
+            );
+        })
         .await
-        .map_err(|e| format!("idevice error: {:?}", e))?;
-    log::info!("Uploaded pairing file to device {}", &udid);
-    Ok(())
+    })
+    .await
+}
+
+// Aborts a running operation by the id its command was handed
+#[tauri::command]
+fn cancel_operation(app: tauri::AppHandle, operation_id: String) -> Result<(), AppError> {
+    operations::cancel(&app, &operation_id)
+}
+
+// Answers a pairing-pin-request with the code shown on the device; null cancels pairing
+#[tauri::command]
+fn submit_pairing_pin(
+    app: tauri::AppHandle,
+    udid: String,
+    pin: Option<String>,
+) -> Result<(), AppError> {
+    pin_prompt::submit(&app, &udid, pin).map_err(AppError::InvalidInput)
+}
+
+// Whether the companion app is installed, recent enough and can receive the pairing file
+#[tauri::command]
+async fn check_companion_app(udid: String) -> Result<companion::CompanionStatus, AppError> {
+    let device = locate(&udid, Route::Any, "check_companion_app").await?;
+    companion::verify(&device).await.map_err(AppError::from)
 }
 
 // get_device_in_dev_mode
 #[tauri::command]
-async fn get_device_in_dev_mode(udid: String) -> Result<bool, String> {
+async fn get_device_in_dev_mode(udid: String) -> Result<bool, AppError> {
     log::info!("Checking if device with UDID: {} is in dev mode", &udid);
-    let in_dev_mode = idevice_helpers::is_device_in_dev_mode(&udid)
-        .await
-        .map_err(|e| format!("idevice error: {:?}", e))?;
+    let device = locate(&udid, Route::Any, "get_device_in_dev_mode").await?;
+    let in_dev_mode = idevice_helpers::is_device_in_dev_mode(&device).await?;
     log::info!(
         "Device with UDID: {} is in dev mode: {}",
         &udid,
@@ -106,14 +508,13 @@ async fn get_device_in_dev_mode(udid: String) -> Result<bool, String> {
 
 // reveal dev mode option in ui
 #[tauri::command]
-async fn reveal_dev_mode(udid: String) -> Result<(), String> {
+async fn reveal_dev_mode(udid: String) -> Result<(), AppError> {
     log::info!(
         "Revealing developer mode option in UI for device with UDID: {}",
         &udid
     );
-    idevice_helpers::reveal_dev_mode(&udid)
-        .await
-        .map_err(|e| format!("idevice error: {:?}", e))?;
+    let device = locate(&udid, Route::Any, "reveal_dev_mode").await?;
+    idevice_helpers::reveal_dev_mode(&device).await?;
     log::info!(
         "Revealed developer mode option in UI for device with UDID: {}",
         &udid
@@ -121,15 +522,376 @@ async fn reveal_dev_mode(udid: String) -> Result<(), String> {
     Ok(())
 }
 
+#[tauri::command]
+async fn get_wifi_debugging(udid: String) -> Result<bool, AppError> {
+    let device = locate(&udid, Route::Any, "get_wifi_debugging").await?;
+    Ok(wifi_debugging::is_enabled(&device).await?)
+}
+
+// Fails if the device keeps the old value, e.g. because a profile enforces it
+#[tauri::command]
+async fn set_wifi_debugging(
+    app: tauri::AppHandle,
+    udid: String,
+    enabled: bool,
+) -> Result<(), AppError> {
+    log::info!(
+        "Setting Wi-Fi debugging to {} for device {}",
+        enabled,
+        &udid
+    );
+    let device = locate(&udid, Route::Any, "set_wifi_debugging").await?;
+    wifi_debugging::set_enabled(&device, enabled).await?;
+    device_watcher::refresh(&app, &udid);
+    Ok(())
+}
+
+// Walks the device through enabling developer mode, reporting each step as developer-mode-stage events
+#[tauri::command]
+async fn enable_developer_mode(
+    app: tauri::AppHandle,
+    udid: String,
+    on_start: tauri::ipc::Channel<operations::OperationStarted>,
+) -> Result<(), AppError> {
+    let on_start = operations::send_to(&on_start);
+    operations::run(&app, "enable_developer_mode", &udid, on_start, async {
+        log::info!("Enabling developer mode for device with UDID: {}", &udid);
+        let device = locate(&udid, Route::Any, "enable_developer_mode").await?;
+        developer_mode::enable_developer_mode(
+            &device,
+            developer_mode::DEVELOPER_MODE_TIMEOUT,
+            |stage| {
+                let _ = app.emit(
+                    developer_mode::DEVELOPER_MODE_STAGE_EVENT,
+                    developer_mode::DeveloperModeStageEvent {
+                        udid: udid.clone(),
+                        stage,
+                    },
+                );
+            },
+        )
+        .await?;
+        device_watcher::refresh(&app, &udid);
+        log::info!("Developer mode enabled for device with UDID: {}", &udid);
+        Ok(())
+    })
+    .await
+}
+
+// Mounts the developer disk image from image_dir unless one is already mounted; progress arrives as ddi-progress events
+#[tauri::command]
+async fn mount_developer_image(
+    app: tauri::AppHandle,
+    udid: String,
+    image_dir: String,
+    on_start: tauri::ipc::Channel<operations::OperationStarted>,
+) -> Result<ddi::MountOutcome, AppError> {
+    let on_start = operations::send_to(&on_start);
+    operations::run(&app, "mount_developer_image", &udid, on_start, async {
+        log::info!(
+            "Mounting developer disk image on device with UDID: {}",
+            &udid
+        );
+        let device = locate(&udid, Route::Any, "mount_developer_image").await?;
+        let outcome =
+            ddi::mount_developer_image(&device, std::path::Path::new(&image_dir), |progress| {
+                let _ = app.emit(
+                    ddi::DDI_PROGRESS_EVENT,
+                    ddi::DdiProgressEvent {
+                        udid: udid.clone(),
+                        progress,
+                    },
+                );
+            })
+            .await?;
+        log::info!("Developer disk image on {}: {:?}", &udid, outcome);
+        Ok(outcome)
+    })
+    .await
+}
+
+// Stored pairing files, newest first
+#[tauri::command]
+fn list_pairings() -> Result<Vec<pairing_store::PairingEntry>, AppError> {
+    pairing_store::list().map_err(AppError::Io)
+}
+
+// Looks up a store entry by an id the frontend passed in
+fn stored_entry(id: &str) -> Result<pairing_store::PairingEntry, AppError> {
+    pairing_store::get(id)
+        .map_err(AppError::Io)?
+        .ok_or_else(|| AppError::InvalidInput(format!("no stored pairing {}", id)))
+}
+
+#[tauri::command]
+fn show_pairing(id: String) -> Result<pairing_store::PairingDetails, AppError> {
+    ensure_store_unlocked()?;
+    pairing_store::details(stored_entry(&id)?).map_err(AppError::Io)
+}
+
+// Writes a stored pairing to destination as the plaintext file the companion app imports
+#[tauri::command]
+fn export_pairing(id: String, destination: String) -> Result<String, AppError> {
+    ensure_store_unlocked()?;
+    let entry = stored_entry(&id)?;
+    pairing_store::export(&entry.id, std::path::Path::new(&destination)).map_err(AppError::Io)?;
+    Ok(destination)
+}
+
+// Uploads a stored pairing file to its device again
+#[tauri::command]
+async fn reupload_pairing(
+    app: tauri::AppHandle,
+    id: String,
+    on_start: tauri::ipc::Channel<operations::OperationStarted>,
+) -> Result<companion::CompanionStatus, AppError> {
+    ensure_store_unlocked()?;
+    let entry = stored_entry(&id)?;
+    let pairing_file = pairing_store::pairing_file(&id).map_err(AppError::Io)?;
+    let on_start = operations::send_to(&on_start);
+    operations::run(&app, "reupload_pairing", &entry.udid, on_start, async {
+        log::info!("Re-uploading pairing {} to device {}", &id, &entry.udid);
+        let device = locate(&entry.udid, Route::Any, "reupload_pairing").await?;
+        upload_stored_pairing(&device, &entry, &pairing_file, &|_| {}).await
+    })
+    .await
+}
+
+// Checks a stored pairing over Wi-Fi, the way the companion app connects: entry id, or the
+// device's newest one
+#[tauri::command]
+async fn verify_pairing(
+    app: tauri::AppHandle,
+    udid: String,
+    id: Option<String>,
+    on_start: tauri::ipc::Channel<operations::OperationStarted>,
+) -> Result<pairing_verify::PairingVerification, AppError> {
+    let newest = pairing_store::latest(&udid).map_err(AppError::Io)?;
+    let entry = match id {
+        Some(id) => stored_entry(&id)?,
+        None => newest
+            .clone()
+            .ok_or_else(|| AppError::InvalidInput(format!("no stored pairing for {}", udid)))?,
+    };
+    if entry.udid != udid {
+        return Err(AppError::InvalidInput(format!(
+            "pairing {} belongs to {}",
+            entry.id, entry.udid
+        )));
+    }
+    ensure_store_unlocked()?;
+    let pairing_file = pairing_store::pairing_file(&entry.id).map_err(AppError::Io)?;
+    let newest = match newest {
+        Some(newest) if newest.id != entry.id => {
+            Some(pairing_store::pairing_file(&newest.id).map_err(AppError::Io)?)
+        }
+        _ => None,
+    };
+    let on_start = operations::send_to(&on_start);
+    operations::run(&app, "verify_pairing", &udid, on_start, async {
+        Ok(
+            pairing_verify::verify_pairing(&udid, &entry.hostname, &pairing_file, newest.as_ref())
+                .await,
+        )
+    })
+    .await
+}
+
+// Decommissions a device: drops this tool's remote pairings from it, deletes the
+// uploaded pairing file and forgets the stored ones
+#[tauri::command]
+async fn unpair_device(
+    app: tauri::AppHandle,
+    udid: String,
+    on_start: tauri::ipc::Channel<operations::OperationStarted>,
+) -> Result<pairing::UnpairReport, AppError> {
+    let on_start = operations::send_to(&on_start);
+    operations::run(&app, "unpair_device", &udid, on_start, async {
+        ensure_store_unlocked()?;
+        let device = locate(&udid, Route::Usb, "unpair_device").await?;
+        let entries = pairing_store::for_device(&udid).map_err(AppError::Io)?;
+
+        // One attempt per set of keys: the current identity's first, then the newest
+        // stored file for any other. Rotation changes the identifier, and devices
+        // paired side by side in a batch can end up with different keys under one
+        let mut candidates = Vec::new();
+        if let Some(identity) = host_identity::load().map_err(AppError::Io)? {
+            candidates.push((identity.hostname, identity.pairing_file));
+        }
+        for entry in &entries {
+            let pairing_file = pairing_store::pairing_file(&entry.id).map_err(AppError::Io)?;
+            if !candidates
+                .iter()
+                .any(|(_, f)| f.public_key_bytes() == pairing_file.public_key_bytes())
+            {
+                candidates.push((entry.hostname.clone(), pairing_file));
+            }
+        }
+
+        let mut report = pairing::UnpairReport::default();
+        for (hostname, pairing_file) in &candidates {
+            let removed = pairing::remove_remote_pairing(&device, hostname, pairing_file).await?;
+            if removed {
+                report.removed_hosts.push(hostname.clone());
+            }
+        }
+        // only reached once the device confirmed every removal
+        report.removed_upload = pairing::remove_uploaded_pairing_file(&device).await?;
+
+        for entry in &entries {
+            pairing_store::delete(&entry.id).map_err(AppError::Io)?;
+        }
+        report.deleted_entries = entries.len();
+        log::info!("Unpaired device {}: {:?}", &udid, report);
+        Ok(report)
+    })
+    .await
+}
+
+#[tauri::command]
+fn delete_pairing(id: String) -> Result<(), AppError> {
+    let entry = stored_entry(&id)?;
+    pairing_store::delete(&entry.id).map_err(AppError::Io)
+}
+
+// Host name and identifier devices see this installation as
+#[tauri::command]
+fn get_host_identity() -> Result<host_identity::HostIdentityInfo, AppError> {
+    ensure_store_unlocked()?;
+    Ok(host_identity::load_or_create()
+        .map_err(AppError::Io)?
+        .info())
+}
+
+// Starts over with a new host identity; devices need to be set up again afterwards
+#[tauri::command]
+fn rotate_host_identity() -> Result<host_identity::HostIdentityInfo, AppError> {
+    ensure_store_unlocked()?;
+    Ok(host_identity::rotate().map_err(AppError::Io)?.info())
+}
+
+#[tauri::command]
+fn get_host_name_template() -> host_name::HostNameTemplate {
+    settings::load().host_name
+}
+
+// What the template would name this computer, or why it can't be used
+#[tauri::command]
+fn preview_host_name(template: host_name::HostNameTemplate) -> Result<String, AppError> {
+    template.render().map_err(AppError::InvalidInput)
+}
+
+// Saves the template and rotates the host identity so the next pairing goes out under the new name;
+// devices paired under the old one keep listing it until they are unpaired
+#[tauri::command]
+fn set_host_name_template(
+    template: host_name::HostNameTemplate,
+) -> Result<host_identity::HostIdentityInfo, AppError> {
+    template.render().map_err(AppError::InvalidInput)?;
+    ensure_store_unlocked()?;
+
+    // the new identity takes its name from the saved settings, so they go first and back
+    // again if it can't be created
+    let previous = settings::load();
+    let mut settings = previous.clone();
+    settings.host_name = template;
+    settings::save(&settings).map_err(AppError::Io)?;
+
+    match host_identity::rotate().map_err(AppError::Io) {
+        Ok(identity) => Ok(identity.info()),
+        Err(e) => {
+            if let Err(undo) = settings::save(&previous) {
+                log::error!("Could not restore the host name template: {}", undo);
+            }
+            Err(e)
+        }
+    }
+}
+
+// Whether stored pairing files are encrypted, with which key and whether it is at hand
+#[tauri::command]
+fn get_pairing_encryption() -> Result<vault::VaultStatus, AppError> {
+    vault::status()
+}
+
+// Encrypts the stored pairing files and host keys with a key kept in the OS secret store;
+// passphrase is only used where there is no secret store
+#[tauri::command]
+fn enable_pairing_encryption(passphrase: Option<String>) -> Result<vault::VaultStatus, AppError> {
+    let source = vault::enable(passphrase.as_deref())?;
+    let count = match rewrite_store(&|bytes| vault::seal(vault::open(bytes)?)) {
+        Ok(count) => count,
+        Err(e) => {
+            // files sealed before the failure are opened again while the key is still around
+            log::error!(
+                "Encrypting the pairing store failed, turning it back off: {}",
+                e
+            );
+            if let Err(undo) = rewrite_store(&vault::open).and_then(|_| vault::disable()) {
+                log::error!("Could not turn pairing store encryption back off: {}", undo);
+            }
+            return Err(e);
+        }
+    };
+    log::info!("Encrypted {} stored pairing files with {:?}", count, source);
+    vault::status()
+}
+
+// Rewrites the host keys and every stored pairing file, returning how many pairing files there were
+fn rewrite_store(rewrite: &impl Fn(Vec<u8>) -> Result<Vec<u8>, String>) -> Result<usize, AppError> {
+    host_identity::rewrite_keys(rewrite).map_err(AppError::Io)?;
+    pairing_store::rewrite_pairing_files(rewrite).map_err(AppError::Io)
+}
+
+// Unlocks passphrase-protected pairing files until the app quits
+#[tauri::command]
+fn unlock_pairing_store(passphrase: String) -> Result<vault::VaultStatus, AppError> {
+    vault::unlock(&passphrase)?;
+    vault::status()
+}
+
+// Decrypts everything back to plain files, then forgets the key
+#[tauri::command]
+fn disable_pairing_encryption() -> Result<vault::VaultStatus, AppError> {
+    ensure_store_unlocked()?;
+    let count = rewrite_store(&vault::open)?;
+    vault::disable()?;
+    log::info!("Decrypted {} stored pairing files", count);
+    vault::status()
+}
+
+// usbmuxd endpoint from the settings file; null means the env var or platform default
+#[tauri::command]
+fn get_usbmuxd_address() -> Option<String> {
+    settings::load().usbmuxd_address
+}
+
+// Point the app at another usbmuxd (socket path or host:port), or back to the default with null
+#[tauri::command]
+fn set_usbmuxd_address(app: tauri::AppHandle, address: Option<String>) -> Result<(), AppError> {
+    let address = address
+        .map(|a| a.trim().to_string())
+        .filter(|a| !a.is_empty());
+    muxer::configure(address.as_deref()).map_err(AppError::InvalidInput)?;
+
+    let mut settings = settings::load();
+    settings.usbmuxd_address = address;
+    settings::save(&settings).map_err(AppError::Io)?;
+
+    // the live device table belongs to the old endpoint
+    device_watcher::reconnect(&app);
+    Ok(())
+}
+
 // Command wrappers for frontend invocation
 #[tauri::command]
-fn check_apple_drivers() -> Result<String, String> {
-    idevice_helpers::check_apple_drivers()
+fn check_apple_drivers() -> Result<String, AppError> {
+    idevice_helpers::check_apple_drivers().map_err(AppError::Other)
 }
 
 #[tauri::command]
-fn install_apple_drivers() -> Result<String, String> {
-    idevice_helpers::install_apple_drivers()
+fn install_apple_drivers() -> Result<String, AppError> {
+    idevice_helpers::install_apple_drivers().map_err(AppError::Other)
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -139,13 +901,53 @@ pub fn run() {
 
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
+        .manage(device_watcher::DeviceWatcher::default())
+        .manage(pin_prompt::PinPrompts::default())
+        .manage(operations::Operations::default())
+        .setup(|app| {
+            if let Err(e) = muxer::configure(settings::load().usbmuxd_address.as_deref()) {
+                log::warn!("Ignoring configured usbmuxd address: {}", e);
+            }
+            device_watcher::start(app.handle().clone());
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             get_devices,
+            subscribe_devices,
+            pair_device,
+            submit_pairing_pin,
+            cancel_operation,
             generate_pairing_file,
             get_app_data_folder,
+            preflight,
             setup_device,
+            setup_devices,
+            check_companion_app,
+            install_app,
             get_device_in_dev_mode,
             reveal_dev_mode,
+            get_wifi_debugging,
+            set_wifi_debugging,
+            enable_developer_mode,
+            mount_developer_image,
+            list_pairings,
+            show_pairing,
+            export_pairing,
+            reupload_pairing,
+            delete_pairing,
+            verify_pairing,
+            unpair_device,
+            get_host_identity,
+            rotate_host_identity,
+            get_host_name_template,
+            preview_host_name,
+            set_host_name_template,
+            get_pairing_encryption,
+            enable_pairing_encryption,
+            unlock_pairing_store,
+            disable_pairing_encryption,
+            get_usbmuxd_address,
+            set_usbmuxd_address,
             check_apple_drivers,
             install_apple_drivers
         ])
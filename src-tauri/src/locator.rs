@@ -0,0 +1,127 @@
+//! Resolving a UDID to something device services can connect through.
+//!
+//! Every operation takes a [`DeviceLocator`] instead of looking the device up
+//! in usbmuxd itself. The locator is resolved once per command and holds the
+//! provider, so operations that run back to back (generate then upload, say)
+//! share a single lookup.
+
+use std::{borrow::Borrow, net::IpAddr};
+
+use idevice::{
+    pairing_file::PairingFile,
+    provider::UsbmuxdProvider,
+    usbmuxd::{Connection, UsbmuxdDevice},
+    IdeviceError,
+};
+
+use crate::muxer;
+
+/// Label usbmuxd sees on every connection the app opens.
+const PROVIDER_LABEL: &str = "idevice_pair";
+
+/// Which usbmuxd routes an operation can use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    /// USB when plugged in, otherwise Wi-Fi.
+    Any,
+    /// USB only, for services (trust dialog, CoreDeviceProxy) that aren't offered over Wi-Fi.
+    Usb,
+    /// Wi-Fi only, for checks that have to go over the network even while plugged in.
+    Network,
+}
+
+/// A device resolved from its UDID, with the provider services connect through.
+pub struct DeviceLocator {
+    device: UsbmuxdDevice,
+    provider: UsbmuxdProvider,
+    // name of the operation the locator was resolved for, used to tag logs
+    op: &'static str,
+}
+
+impl DeviceLocator {
+    /// Find `udid` in usbmuxd over `route`. `op` names the calling operation in logs.
+    ///
+    /// Returns `Err(IdeviceError::DeviceNotFound)` if the device isn't attached
+    /// over an acceptable route.
+    pub async fn locate(udid: &str, route: Route, op: &'static str) -> Result<Self, IdeviceError> {
+        log::debug!("{}: connecting to usbmuxd", op);
+        let mut uc = muxer::connect().await?;
+        let devices = uc.get_devices().await?;
+        log::debug!("{}: found {} devices", op, devices.len());
+
+        let dev = match route {
+            Route::Any => select_device(devices, udid),
+            Route::Usb => devices
+                .into_iter()
+                .find(|d| d.udid == udid && d.connection_type == Connection::Usb),
+            Route::Network => devices
+                .into_iter()
+                .find(|d| d.udid == udid && matches!(d.connection_type, Connection::Network(_))),
+        }
+        .ok_or(IdeviceError::DeviceNotFound)?;
+        log::info!(
+            "{}: selected device {} ({:?})",
+            op,
+            dev.udid,
+            dev.connection_type
+        );
+
+        Ok(Self::new(dev, op))
+    }
+
+    /// Wrap a usbmuxd entry that has already been looked up.
+    pub fn new(device: UsbmuxdDevice, op: &'static str) -> Self {
+        let provider = device.to_provider(muxer::addr(), PROVIDER_LABEL);
+        Self {
+            device,
+            provider,
+            op,
+        }
+    }
+
+    pub fn udid(&self) -> &str {
+        &self.device.udid
+    }
+
+    pub fn provider(&self) -> &UsbmuxdProvider {
+        &self.provider
+    }
+
+    /// The device's IP address when it was located over Wi-Fi.
+    pub fn network_address(&self) -> Option<IpAddr> {
+        match self.device.connection_type {
+            Connection::Network(addr) => Some(addr),
+            _ => None,
+        }
+    }
+
+    /// The pair record usbmuxd holds for this device.
+    pub async fn pair_record(&self) -> Result<PairingFile, IdeviceError> {
+        log::debug!("{}: reading pair record for {}", self.op, self.device.udid);
+        muxer::connect()
+            .await?
+            .get_pair_record(&self.device.udid)
+            .await
+    }
+}
+
+/// Pick the route to use for `udid`: USB when the device is plugged in,
+/// otherwise its network (Wi-Fi) entry.
+pub fn select_device<D: Borrow<UsbmuxdDevice>>(
+    devices: impl IntoIterator<Item = D>,
+    udid: &str,
+) -> Option<D> {
+    let mut network = None;
+    for dev in devices {
+        let d = dev.borrow();
+        if d.udid != udid {
+            continue;
+        }
+        match d.connection_type {
+            Connection::Usb => return Some(dev),
+            Connection::Network(_) => network = network.or(Some(dev)),
+            Connection::Unknown(_) => {}
+        }
+    }
+    network
+}
@@ -0,0 +1,108 @@
+//! Where to reach usbmuxd.
+//!
+//! Defaults to the platform socket (`/var/run/usbmuxd`, or `127.0.0.1:27015`
+//! on Windows). The `usbmuxdAddress` setting, or failing that the
+//! `USBMUXD_SOCKET_ADDRESS` env var, can point the app at another socket path
+//! or at a TCP `host:port` such as netmuxd or a usbmuxd forwarded over SSH.
+
+use std::{net::ToSocketAddrs, sync::RwLock};
+
+use idevice::{
+    usbmuxd::{UsbmuxdAddr, UsbmuxdConnection},
+    IdeviceError,
+};
+
+/// Same variable libimobiledevice and idevice honour.
+pub const USBMUXD_ADDRESS_ENV: &str = "USBMUXD_SOCKET_ADDRESS";
+
+// Endpoint from the settings file; takes precedence over the env var
+static CONFIGURED: RwLock<Option<UsbmuxdAddr>> = RwLock::new(None);
+
+/// Parse a socket path or a `host:port` pair. Host names are resolved here,
+/// so a bad address is reported when it is configured rather than on first use.
+pub fn parse_addr(address: &str) -> Result<UsbmuxdAddr, String> {
+    let address = address.trim();
+    if address.is_empty() {
+        return Err("usbmuxd address is empty".into());
+    }
+
+    // Unix socket paths never contain a colon; anything else is host:port
+    #[cfg(unix)]
+    if !address.contains(':') {
+        return Ok(UsbmuxdAddr::UnixSocket(address.to_string()));
+    }
+
+    let addr = address
+        .to_socket_addrs()
+        .map_err(|e| format!("invalid usbmuxd address {}: {}", address, e))?
+        .next()
+        .ok_or_else(|| format!("usbmuxd address {} did not resolve", address))?;
+    Ok(UsbmuxdAddr::TcpSocket(addr))
+}
+
+/// Set (or with `None`, clear) the endpoint from the settings file.
+pub fn configure(address: Option<&str>) -> Result<(), String> {
+    let addr = address.map(parse_addr).transpose()?;
+    log::info!("muxer: usbmuxd endpoint set to {:?}", addr);
+    *CONFIGURED.write().unwrap() = addr;
+    Ok(())
+}
+
+/// The endpoint every usbmuxd connection and provider should use.
+pub fn addr() -> UsbmuxdAddr {
+    if let Some(addr) = CONFIGURED.read().unwrap().clone() {
+        return addr;
+    }
+    match std::env::var(USBMUXD_ADDRESS_ENV) {
+        Ok(address) => parse_addr(&address).unwrap_or_else(|e| {
+            log::warn!("muxer: ignoring {}: {}", USBMUXD_ADDRESS_ENV, e);
+            UsbmuxdAddr::default()
+        }),
+        Err(_) => UsbmuxdAddr::default(),
+    }
+}
+
+/// Open a new connection to the configured usbmuxd.
+pub async fn connect() -> Result<UsbmuxdConnection, IdeviceError> {
+    addr().connect(0).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_parsing() {
+        let cases: &[(&str, Option<&str>)] = &[
+            ("127.0.0.1:27015", Some("127.0.0.1:27015")),
+            ("  127.0.0.1:27015\n", Some("127.0.0.1:27015")),
+            ("[::1]:27015", Some("[::1]:27015")),
+            ("", None),
+            ("   ", None),
+            ("127.0.0.1:", None),
+            ("127.0.0.1:port", None),
+            ("127.0.0.1:70000", None),
+        ];
+        for (address, expected) in cases {
+            let parsed = parse_addr(address);
+            match (expected, &parsed) {
+                (Some(expected), Ok(UsbmuxdAddr::TcpSocket(addr))) => {
+                    assert_eq!(addr.to_string(), *expected, "{:?}", address)
+                }
+                (None, Err(_)) => {}
+                _ => panic!("{:?} parsed to {:?}", address, parsed),
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn socket_paths() {
+        for path in ["/var/run/usbmuxd", " /tmp/usbmuxd.sock ", "usbmuxd"] {
+            match parse_addr(path) {
+                Ok(UsbmuxdAddr::UnixSocket(parsed)) => assert_eq!(parsed, path.trim()),
+                other => panic!("{:?} parsed to {:?}", path, other),
+            }
+        }
+    }
+}
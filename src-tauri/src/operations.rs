@@ -0,0 +1,128 @@
+//! Long-running commands the user can cancel.
+//!
+//! Each one runs under an operation id, handed to the invoking command through
+//! `on_start` (commands pass it on as the first message on their channel) and
+//! announced in an `operation` event, both before any work starts.
+//! `cancel_operation` with that id aborts the command's
+//! future, which drops whatever it holds open (the CoreDeviceProxy tunnel,
+//! XPC and AFC streams) and makes the command fail with `AppError::Cancelled`.
+
+use std::{collections::HashMap, future::Future, sync::Mutex};
+
+use futures::future::{AbortHandle, Abortable};
+use serde::Serialize;
+use tauri::{ipc::Channel, AppHandle, Emitter, Manager};
+
+use crate::error::AppError;
+
+pub const OPERATION_EVENT: &str = "operation";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OperationStatus {
+    Running,
+    Succeeded,
+    Failed,
+    /// Stopped by `cancel_operation` or by the user declining on the way.
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OperationEvent {
+    pub operation_id: String,
+    /// The command running, e.g. `setup_device`.
+    pub kind: &'static str,
+    pub udid: String,
+    pub status: OperationStatus,
+}
+
+/// The id a command's operation runs under, for `cancel_operation`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OperationStarted {
+    pub operation_id: String,
+    pub udid: String,
+}
+
+/// Managed state holding an abort handle per running operation.
+#[derive(Default)]
+pub struct Operations {
+    running: Mutex<HashMap<String, AbortHandle>>,
+}
+
+/// Run `operation` as a cancellable `kind` operation on `udid`, passing its id
+/// to `on_start` first.
+pub async fn run<T>(
+    app: &AppHandle,
+    kind: &'static str,
+    udid: &str,
+    on_start: impl FnOnce(OperationStarted),
+    operation: impl Future<Output = Result<T, AppError>>,
+) -> Result<T, AppError> {
+    let id = uuid::Uuid::new_v4().to_string();
+    let (handle, registration) = AbortHandle::new_pair();
+    app.state::<Operations>()
+        .running
+        .lock()
+        .unwrap()
+        .insert(id.clone(), handle);
+    on_start(OperationStarted {
+        operation_id: id.clone(),
+        udid: udid.to_string(),
+    });
+
+    let emit = |status| {
+        let _ = app.emit(
+            OPERATION_EVENT,
+            OperationEvent {
+                operation_id: id.clone(),
+                kind,
+                udid: udid.to_string(),
+                status,
+            },
+        );
+    };
+    emit(OperationStatus::Running);
+    log::debug!("operations: {} started {} for {}", id, kind, udid);
+
+    let result = Abortable::new(operation, registration)
+        .await
+        .unwrap_or(Err(AppError::Cancelled));
+
+    app.state::<Operations>()
+        .running
+        .lock()
+        .unwrap()
+        .remove(&id);
+    let status = match &result {
+        Ok(_) => OperationStatus::Succeeded,
+        Err(AppError::Cancelled) => OperationStatus::Cancelled,
+        Err(_) => OperationStatus::Failed,
+    };
+    log::info!("operations: {} ({} for {}) {:?}", id, kind, udid, status);
+    emit(status);
+    result
+}
+
+/// `on_start` for commands whose only channel is the one for their operation id.
+pub fn send_to(channel: &Channel<OperationStarted>) -> impl FnOnce(OperationStarted) + '_ {
+    move |started| {
+        let _ = channel.send(started);
+    }
+}
+
+/// Abort the running operation `id`. Errors if there is none by that id,
+/// e.g. because it already finished.
+pub fn cancel(app: &AppHandle, id: &str) -> Result<(), AppError> {
+    let handle = app
+        .state::<Operations>()
+        .running
+        .lock()
+        .unwrap()
+        .remove(id)
+        .ok_or_else(|| AppError::InvalidInput(format!("no running operation {}", id)))?;
+    log::info!("operations: cancelling {}", id);
+    handle.abort();
+    Ok(())
+}
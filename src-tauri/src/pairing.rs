@@ -1,183 +1,359 @@
-//! Helper to generate a pairing file for a connected device given its UDID.
+//! Helper to generate a pairing file for a connected device and upload it,
+//! and to take both back off the device again.
 
-use uuid::Uuid;
+use std::{
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
 use idevice::{
-    afc::{opcode::AfcFopenMode, AfcClient},
+    afc::{errors::AfcError, opcode::AfcFopenMode},
     core_device_proxy::CoreDeviceProxy,
-    house_arrest::{self, HouseArrestClient},
-    lockdown::LockdownClient,
-    pairing_file::PairingFile,
-    remote_pairing::{RemotePairingClient, RpPairingFile},
+    remote_pairing::{
+        errors::RemotePairingError, RemotePairingClient, RpPairingFile, RpPairingSocketProvider,
+    },
     rsd::RsdHandshake,
-    usbmuxd::{UsbmuxdAddr, UsbmuxdConnection},
+    tcp::handle::{AdapterHandle, StreamHandle},
     IdeviceError, IdeviceService, RemoteXpcClient,
 };
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::{
+    companion::{self, CompanionStatus},
+    error::AppError,
+    host_identity::HostIdentity,
+    locator::DeviceLocator,
+    setup_progress::SetupStage,
+};
+
+/// How long the user gets to type in the code shown on the device.
+pub const PIN_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How many times pair verify is tried after pairing before giving up on the
+/// device persisting it, and the wait before the first retry (doubled each time).
+const COMMIT_ATTEMPTS: u32 = 5;
+const COMMIT_BACKOFF: Duration = Duration::from_millis(250);
 
-use plist::{Dictionary as PlistDict, Value as PlistValue};
+/// Where the companion app picks the pairing file up.
+const UPLOADED_PAIRING_FILE: &str = "/Documents/rpPairingFile.plist";
 
-fn pairing_hostname() -> String {
-    let suffix: String = uuid::Uuid::new_v4()
-        .simple()
-        .to_string()
-        .chars()
-        .take(6)
-        .collect();
-    format!("Auto Capture Pairing-{suffix}")
+const UNTRUSTED_TUNNEL_SERVICE: &str = "com.apple.internal.dt.coredevice.untrusted.tunnelservice";
+
+/// What decommissioning a device took off it and out of the local store.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnpairReport {
+    /// Host names whose remote pairing the device dropped.
+    pub removed_hosts: Vec<String>,
+    /// The companion app had an uploaded pairing file, now deleted.
+    pub removed_upload: bool,
+    /// Store entries deleted for the device.
+    pub deleted_entries: usize,
 }
 
-/// Generate a new pairing file for the device with the given `udid`.
+/// Generate a new pairing file for `device`.
 ///
 /// This will:
-/// - connect to usbmuxd,
-/// - locate the device by UDID,
-/// - connect to lockdown on that device,
-/// - fetch the host BUID and tweak it (to avoid invalidating an already-connected pairing),
-/// - call lockdown pair to create a new PairingFile,
-/// - set the PairingFile.udid to the provided UDID and return it.
+/// - open the untrusted tunnel service over CoreDeviceProxy,
+/// - run remote pairing under `identity`,
+/// - confirm the device persisted it by pair verifying on a fresh connection,
+/// - and return the resulting RpPairingFile.
 ///
-/// Returns Err(IdeviceError::DeviceNotFound) if there is no connected device
-/// with the provided UDID.
-pub async fn generate_pairing_file_for_udid(udid: &str) -> Result<RpPairingFile, IdeviceError> {
-    log::info!("generate_pairing_file_for_udid: starting for udid={}", udid);
-
-    // Connect to usbmuxd
-    log::debug!("generate_pairing_file_for_udid: connecting to usbmuxd");
-    let mut uc: UsbmuxdConnection = UsbmuxdConnection::default().await?;
-    log::debug!("generate_pairing_file_for_udid: connected to usbmuxd");
-
-    // Get device list and find by UDID
-    let devices = uc.get_devices().await?;
-    log::debug!(
-        "generate_pairing_file_for_udid: found {} devices",
-        devices.len()
-    );
-    let dev = match devices.into_iter().find(|d| d.udid == udid) {
-        Some(d) => d,
-        None => return Err(IdeviceError::DeviceNotFound),
-    };
+/// If the device still trusts `identity`'s keys they are reused as is;
+/// otherwise pairing replaces them in `identity`, which the caller should save.
+///
+/// Wi-Fi debugging is left as it is; see `wifi_debugging::set_enabled`.
+///
+/// CoreDeviceProxy is only reachable over USB, so `device` must be located
+/// with `Route::Usb`.
+///
+/// Most devices only ask the user to accept the pairing and the library's
+/// default PIN is used. When the device shows a code instead, `request_pin`
+/// is awaited for it; resolving to `None` cancels pairing.
+///
+/// `on_stage` is called as each step starts.
+pub async fn generate_pairing_file<F, Fut>(
+    device: &DeviceLocator,
+    identity: &mut HostIdentity,
+    request_pin: F,
+    on_stage: &impl Fn(SetupStage),
+) -> Result<RpPairingFile, AppError>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Option<String>>,
+{
+    let udid = device.udid();
+    log::info!("generate_pairing_file: starting for udid={}", udid);
 
-    log::info!(
-        "generate_pairing_file_for_udid: selected device {}",
-        dev.udid
-    );
+    let hostname = identity.hostname.clone();
 
-    let pairing_file = uc.get_pair_record(udid).await?;
+    let (mut adapter, tunnel_service_port) = untrusted_tunnel_service(device, on_stage).await?;
 
-    // Build a provider for lockdown and connect
-    let provider = dev.to_provider(UsbmuxdAddr::default(), "idevice_pair");
-    let mut lc = LockdownClient::connect(&provider).await?;
-    lc.start_session(&pairing_file).await?;
+    on_stage(SetupStage::Pairing);
+    let started = Instant::now();
+    let remote_xpc = open_pairing_channel(&mut adapter, tunnel_service_port).await?;
+    let rp_pairing_file = &mut identity.pairing_file;
+    let mut pairing_client = RemotePairingClient::new(remote_xpc, &hostname, rp_pairing_file);
+    connect_with_pin(&mut pairing_client, &request_pin).await?;
+    log::info!(
+        "generate_pairing_file: paired with {} in {:?}",
+        udid,
+        started.elapsed()
+    );
 
-    lc.set_value(
-        "EnableWifiDebugging",
-        true.into(),
-        Some("com.apple.mobile.wireless_lockdown"),
+    on_stage(SetupStage::ConfirmCommit);
+    confirm_commit(
+        device,
+        &mut adapter,
+        tunnel_service_port,
+        &hostname,
+        rp_pairing_file,
     )
     .await?;
-    log::debug!(
-        "generate_pairing_file_for_udid: enabled wifi debugging for {}",
-        udid
+
+    log::info!("generate_pairing_file: pairing succeeded for {}", udid);
+    Ok(rp_pairing_file.clone())
+}
+
+/// Wait until the device accepts `pairing_file` in a pair verify on a new
+/// connection, which only happens once it has written the pairing to its
+/// keychain. Right after pairing that can lag behind, so verify is retried
+/// with backoff before the pairing is given up on.
+async fn confirm_commit(
+    device: &DeviceLocator,
+    adapter: &mut AdapterHandle,
+    port: u16,
+    hostname: &str,
+    pairing_file: &mut RpPairingFile,
+) -> Result<(), IdeviceError> {
+    let udid = device.udid();
+    let started = Instant::now();
+    let mut backoff = COMMIT_BACKOFF;
+    for attempt in 1..=COMMIT_ATTEMPTS {
+        let remote_xpc = open_pairing_channel(adapter, port).await?;
+        let mut client = RemotePairingClient::new(remote_xpc, hostname, pairing_file);
+        if verify_keys(&mut client).await? {
+            log::info!(
+                "confirm_commit: {} persisted the pairing, confirmed on attempt {} after {:?}",
+                udid,
+                attempt,
+                started.elapsed()
+            );
+            return Ok(());
+        }
+        log::warn!(
+            "confirm_commit: {} has not persisted the pairing yet (attempt {}/{})",
+            udid,
+            attempt,
+            COMMIT_ATTEMPTS
+        );
+        if attempt < COMMIT_ATTEMPTS {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    log::error!(
+        "confirm_commit: {} never persisted the pairing, gave up after {:?}",
+        udid,
+        started.elapsed()
     );
+    Err(IdeviceError::RemotePairing(
+        RemotePairingError::PairingRejected(format!(
+            "the device accepted the pairing but did not persist it after {} checks",
+            COMMIT_ATTEMPTS
+        )),
+    ))
+}
 
-    let hostname = pairing_hostname();
+/// Pair verify without falling back to pairing. `Ok(false)` means the device
+/// doesn't accept the client's keys.
+async fn verify_keys<R: RpPairingSocketProvider>(
+    client: &mut RemotePairingClient<'_, R>,
+) -> Result<bool, IdeviceError> {
+    client.attempt_pair_verify().await?;
+    match client.validate_pairing().await {
+        Ok(()) => Ok(true),
+        Err(IdeviceError::RemotePairing(RemotePairingError::PairVerifyFailed)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
 
-    let proxy =
-        CoreDeviceProxy::connect(&(dev.to_provider(UsbmuxdAddr::default(), "idevice_pair")))
-            .await?;
+/// Bring up a CoreDeviceProxy tunnel to `device` and find the port of the
+/// untrusted tunnel service remote pairing runs on. USB only.
+async fn untrusted_tunnel_service(
+    device: &DeviceLocator,
+    on_stage: &impl Fn(SetupStage),
+) -> Result<(AdapterHandle, u16), IdeviceError> {
+    on_stage(SetupStage::Tunnel);
+    let proxy = CoreDeviceProxy::connect(device.provider()).await?;
     let rsd_port = proxy.tunnel_info().server_rsd_port;
 
     let adapter = proxy.create_software_tunnel()?;
     let mut adapter = adapter.to_async_handle();
 
+    on_stage(SetupStage::RsdHandshake);
     let rsd_stream = adapter.connect(rsd_port).await?;
     let handshake = RsdHandshake::new(rsd_stream).await?;
 
-    let tunnel_service = handshake
+    let port = handshake
         .services
-        .get("com.apple.internal.dt.coredevice.untrusted.tunnelservice")
-        .ok_or_else(|| IdeviceError::InternalError("Untrusted tunnel service not found".into()))?;
+        .get(UNTRUSTED_TUNNEL_SERVICE)
+        .ok_or(IdeviceError::ServiceNotFound)?
+        .port;
+    Ok((adapter, port))
+}
 
-    let tunnel_service_stream = adapter.connect(tunnel_service.port).await?;
-    let mut remote_xpc = RemoteXpcClient::new(tunnel_service_stream).await?;
+/// A fresh RemoteXPC connection to the untrusted tunnel service.
+async fn open_pairing_channel(
+    adapter: &mut AdapterHandle,
+    port: u16,
+) -> Result<RemoteXpcClient<StreamHandle>, IdeviceError> {
+    let mut remote_xpc = RemoteXpcClient::new(adapter.connect(port).await?).await?;
     remote_xpc.do_handshake().await?;
     let _ = remote_xpc.recv_root().await;
+    Ok(remote_xpc)
+}
 
-    let mut rp_pairing_file = RpPairingFile::generate(&hostname);
-    let mut pairing_client = RemotePairingClient::new(remote_xpc, &hostname, &mut rp_pairing_file);
-    pairing_client
-        .connect(async |_| "000000".to_string(), ())
-        .await?;
+/// Remove the remote pairing `pairing_file` holds from `device`.
+///
+/// Pair verify has to succeed with the file's keys before the device accepts
+/// the request, so `Ok(false)` means the device didn't know them (anymore)
+/// and there was nothing to remove. An unpair the device refuses or doesn't
+/// acknowledge is an error. `device` must be located with `Route::Usb`.
+pub async fn remove_remote_pairing(
+    device: &DeviceLocator,
+    hostname: &str,
+    pairing_file: &RpPairingFile,
+) -> Result<bool, IdeviceError> {
+    let udid = device.udid();
+    log::info!(
+        "remove_remote_pairing: removing {} from udid={}",
+        pairing_file.identifier(),
+        udid
+    );
 
-    // use it to try and force keychain commitment
-    // iOS has trouble commiting I guess
-    let tunnel_service_stream = adapter.connect(tunnel_service.port).await?;
-    let mut remote_xpc = RemoteXpcClient::new(tunnel_service_stream).await?;
-    remote_xpc.do_handshake().await?;
-    let _ = remote_xpc.recv_root().await;
+    let (mut adapter, port) = untrusted_tunnel_service(device, &|_| {}).await?;
+    let remote_xpc = open_pairing_channel(&mut adapter, port).await?;
 
-    let mut pairing_client = RemotePairingClient::new(remote_xpc, &hostname, &mut rp_pairing_file);
-    pairing_client
-        .connect(async |_| "000000".to_string(), ())
-        .await?;
+    let mut pairing_file = pairing_file.clone();
+    let identifier = pairing_file.identifier().to_string();
+    let mut client = RemotePairingClient::new(remote_xpc, hostname, &mut pairing_file);
+    if !verify_keys(&mut client).await? {
+        log::info!(
+            "remove_remote_pairing: {} does not know {}",
+            udid,
+            identifier
+        );
+        return Ok(false);
+    }
+
+    let request = plist::to_value(&serde_json::json!({
+        "request": { "_0": { "unpair": { "_0": { "host": { "identifier": identifier } } } } }
+    }))?;
+    let response = client.send_receive_encrypted_request(request).await?;
+    unpair_acknowledged(&response)?;
 
     log::info!(
-        "generate_pairing_file_for_udid: pairing succeeded for {}",
+        "remove_remote_pairing: removed {} from {}",
+        identifier,
         udid
     );
-    /*log::debug!(
-        "generate_pairing_file_for_udid: pairing_file= {:?}",
-        pairing_file
-    );*/
+    Ok(true)
+}
 
-    Ok(rp_pairing_file)
+/// Check the device's answer to an unpair request. It names the request it
+/// answers on success and an error case otherwise.
+fn unpair_acknowledged(response: &plist::Value) -> Result<(), IdeviceError> {
+    let response = response.as_dictionary().ok_or_else(|| {
+        IdeviceError::UnexpectedResponse(format!("unpair answered with {:?}", response))
+    })?;
+    if response.contains_key("unpair") {
+        return Ok(());
+    }
+    match response.keys().find(|k| k.starts_with("error")) {
+        Some(key) => Err(IdeviceError::RemotePairing(
+            RemotePairingError::PairingRejected(format!(
+                "the device refused to unpair: {:?}",
+                response.get(key)
+            )),
+        )),
+        None => Err(IdeviceError::UnexpectedResponse(format!(
+            "unpair was not acknowledged: {:?}",
+            response
+        ))),
+    }
 }
 
+/// `RemotePairingClient::connect`, asking `request_pin` for the code if the
+/// device shows one. The library expects a PIN no matter what, so a cancelled
+/// or timed out prompt aborts the whole connect instead.
+async fn connect_with_pin<R, F, Fut>(
+    client: &mut RemotePairingClient<'_, R>,
+    request_pin: &F,
+) -> Result<(), AppError>
+where
+    R: RpPairingSocketProvider,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Option<String>>,
+{
+    let aborted = Notify::new();
+    let reason = Mutex::new(None);
+    let pin_callback = |_: ()| async {
+        log::info!("generate_pairing_file: device is showing a pairing code");
+        match tokio::time::timeout(PIN_TIMEOUT, request_pin()).await {
+            Ok(Some(pin)) => return pin,
+            Ok(None) => *reason.lock().unwrap() = Some(AppError::Cancelled),
+            Err(_) => {
+                *reason.lock().unwrap() =
+                    Some(AppError::Timeout("waiting for the pairing code".into()))
+            }
+        }
+        aborted.notify_one();
+        std::future::pending().await
+    };
+
+    tokio::select! {
+        result = client.connect(pin_callback, ()) => result.map_err(AppError::from),
+        _ = aborted.notified() => Err(reason
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or(AppError::Cancelled)),
+    }
+}
+
+/// Write `pairing_file` into the companion app's Documents folder.
+///
+/// The app is checked first; if it can't take the file the upload is skipped
+/// and the returned status says why. On success the status is `Ready`.
 pub async fn upload_pairing_file_to_device(
-    udid: &str,
+    device: &DeviceLocator,
     pairing_file: &RpPairingFile,
-) -> Result<(), IdeviceError> {
+    on_stage: &impl Fn(SetupStage),
+) -> Result<CompanionStatus, IdeviceError> {
+    let udid = device.udid();
     log::info!("upload_pairing_file_to_device: starting for udid={}", udid);
 
-    // Connect to usbmuxd
-    log::debug!("upload_pairing_file_to_device: connecting to usbmuxd");
-    let mut uc: UsbmuxdConnection = UsbmuxdConnection::default().await?;
-    log::debug!("upload_pairing_file_to_device: connected to usbmuxd");
-
-    // Get device list and find by UDID
-    let devices = uc.get_devices().await?;
-    log::debug!(
-        "upload_pairing_file_to_device: found {} devices",
-        devices.len()
-    );
-    let dev = match devices.into_iter().find(|d| d.udid == udid) {
-        Some(d) => d,
-        None => return Err(IdeviceError::DeviceNotFound),
-    };
-    log::info!(
-        "upload_pairing_file_to_device: selected device {}",
-        dev.udid
-    );
-
-    // Build a provider for lockdown and connect
-    let provider = dev.to_provider(UsbmuxdAddr::default(), "idevice_pair");
+    on_stage(SetupStage::VendDocuments);
+    let status = companion::verify(device).await?;
+    if !status.is_ready() {
+        log::warn!(
+            "upload_pairing_file_to_device: companion app on {} is not ready: {:?}",
+            udid,
+            status
+        );
+        return Ok(status);
+    }
 
     // connect to afc
-    log::debug!("upload_pairing_file_to_device: connecting to HouseArrestClient");
-    let ha_client = house_arrest::HouseArrestClient::connect(&provider)
-        .await
-        .map_err(|e| {
-            log::error!("Failed to connect to HouseArrestClient: {:?}", e);
-            e
-        })?;
-    log::debug!("upload_pairing_file_to_device: connected to HouseArrestClient");
-    let mut afc = ha_client
-        .vend_documents("com.halfeatentoast.devcapture")
-        .await
-        .map_err(|e| {
-            log::error!("Failed to vend documents: {:?}", e);
-            e
-        })?;
+    log::debug!("upload_pairing_file_to_device: vending companion documents");
+    let mut afc = companion::documents(device).await.map_err(|e| {
+        log::error!("Failed to vend documents: {:?}", e);
+        e
+    })?;
     log::debug!("upload_pairing_file_to_device: obtained afc client");
 
     // serialize pairing file to plist data
@@ -187,9 +363,10 @@ pub async fn upload_pairing_file_to_device(
         pairing_file_plist.len()
     );
     // write pairing file to device
+    on_stage(SetupStage::WriteFile);
     log::debug!("upload_pairing_file_to_device: opening file on device");
     let mut file = afc
-        .open("/Documents/rpPairingFile.plist", AfcFopenMode::WrOnly)
+        .open(UPLOADED_PAIRING_FILE, AfcFopenMode::WrOnly)
         .await
         .map_err(|e| {
             log::error!("Failed to open file on device: {:?}", e);
@@ -203,5 +380,63 @@ pub async fn upload_pairing_file_to_device(
         "upload_pairing_file_to_device: successfully wrote pairing file to device {}",
         udid
     );
-    Ok(())
+    Ok(status)
+}
+
+/// Delete the uploaded pairing file from the companion app's Documents folder.
+///
+/// Returns whether there was one to delete. Without the companion app there
+/// is nowhere a file could be, so that counts as nothing to delete too.
+pub async fn remove_uploaded_pairing_file(device: &DeviceLocator) -> Result<bool, IdeviceError> {
+    let udid = device.udid();
+    if companion::lookup(device).await?.is_none() {
+        log::debug!(
+            "remove_uploaded_pairing_file: companion app not installed on {}",
+            udid
+        );
+        return Ok(false);
+    }
+
+    let mut afc = companion::documents(device).await?;
+    match afc.remove(UPLOADED_PAIRING_FILE).await {
+        Ok(()) => {
+            log::info!(
+                "remove_uploaded_pairing_file: removed pairing file from {}",
+                udid
+            );
+            Ok(true)
+        }
+        Err(IdeviceError::Afc(AfcError::ObjectNotFound)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(json: serde_json::Value) -> plist::Value {
+        plist::to_value(&json).unwrap()
+    }
+
+    #[test]
+    fn unpair_acknowledgement() {
+        assert!(unpair_acknowledged(&response(serde_json::json!({ "unpair": {} }))).is_ok());
+        assert!(matches!(
+            unpair_acknowledged(&response(serde_json::json!({
+                "errorExtended": { "_0": { "domain": "RemotePairingErrorDomain" } }
+            }))),
+            Err(IdeviceError::RemotePairing(
+                RemotePairingError::PairingRejected(_)
+            ))
+        ));
+        assert!(matches!(
+            unpair_acknowledged(&response(serde_json::json!({ "createListener": {} }))),
+            Err(IdeviceError::UnexpectedResponse(_))
+        ));
+        assert!(matches!(
+            unpair_acknowledged(&response(serde_json::json!("ok"))),
+            Err(IdeviceError::UnexpectedResponse(_))
+        ));
+    }
 }
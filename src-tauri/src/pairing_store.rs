@@ -0,0 +1,288 @@
+//! Every pairing file this installation generated, kept under the app data
+//! folder so it can be exported or uploaded again later.
+//!
+//! Each entry is two files in `pairings/`: `<id>.plist` with the pairing file
+//! itself and `<id>.json` with what we know about it. The plist goes through
+//! `vault`, so it is encrypted when at-rest encryption is on.
+
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use idevice::remote_pairing::RpPairingFile;
+use serde::{Deserialize, Serialize};
+
+use crate::vault;
+
+const STORE_DIR: &str = "pairings";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum UploadStatus {
+    /// Kept locally only, e.g. generated for export.
+    NotUploaded,
+    Uploaded,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PairingEntry {
+    pub id: String,
+    pub udid: String,
+    pub device_name: Option<String>,
+    pub ios_version: Option<String>,
+    /// Host name the device knows this pairing under.
+    pub hostname: String,
+    /// Unix seconds.
+    pub created_at: u64,
+    pub upload_status: UploadStatus,
+    /// Unix seconds of the last successful upload.
+    pub uploaded_at: Option<u64>,
+    /// Why the last upload failed.
+    pub upload_error: Option<String>,
+}
+
+/// An entry together with the pairing file it describes.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PairingDetails {
+    #[serde(flatten)]
+    pub entry: PairingEntry,
+    /// Remote pairing host identifier inside the file.
+    pub identifier: String,
+    /// The pairing file as plist XML.
+    pub plist: String,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn store_dir() -> Result<PathBuf, String> {
+    Ok(PathBuf::from(crate::app_data_folder()?).join(STORE_DIR))
+}
+
+/// Entry ids are generated by us, but they also come back from the frontend.
+fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+fn entry_paths(id: &str) -> Result<(PathBuf, PathBuf), String> {
+    if !valid_id(id) {
+        return Err(format!("invalid pairing id {:?}", id));
+    }
+    let dir = store_dir()?;
+    Ok((
+        dir.join(format!("{}.json", id)),
+        dir.join(format!("{}.plist", id)),
+    ))
+}
+
+/// Store a freshly generated pairing file and return its entry.
+pub fn add(
+    udid: &str,
+    device_name: Option<String>,
+    ios_version: Option<String>,
+    hostname: &str,
+    pairing_file: &RpPairingFile,
+) -> Result<PairingEntry, String> {
+    let dir = store_dir()?;
+    std::fs::create_dir_all(&dir).map_err(|e| format!("failed to create dir: {}", e))?;
+
+    let entry = PairingEntry {
+        id: uuid::Uuid::new_v4().to_string(),
+        udid: udid.to_string(),
+        device_name,
+        ios_version,
+        hostname: hostname.to_string(),
+        created_at: unix_now(),
+        upload_status: UploadStatus::NotUploaded,
+        uploaded_at: None,
+        upload_error: None,
+    };
+    let (_, plist_path) = entry_paths(&entry.id)?;
+    write_pairing_file(&plist_path, pairing_file)?;
+    save(&entry)?;
+    log::info!("pairing_store: stored {} for {}", entry.id, udid);
+    Ok(entry)
+}
+
+/// All entries, newest first. Unreadable entries are skipped.
+pub fn list() -> Result<Vec<PairingEntry>, String> {
+    let dir = store_dir()?;
+    let read_dir = match std::fs::read_dir(&dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("failed to read {}: {}", dir.display(), e)),
+    };
+
+    let mut entries: Vec<PairingEntry> = read_dir
+        .filter_map(|f| f.ok())
+        .map(|f| f.path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|p| match read_entry(&p) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                log::warn!("pairing_store: skipping {}: {}", p.display(), e);
+                None
+            }
+        })
+        .collect();
+    entries.sort_by_key(|e| std::cmp::Reverse(e.created_at));
+    Ok(entries)
+}
+
+/// Entries for `udid`, newest first.
+pub fn for_device(udid: &str) -> Result<Vec<PairingEntry>, String> {
+    Ok(list()?.into_iter().filter(|e| e.udid == udid).collect())
+}
+
+/// The most recent entry for `udid`.
+pub fn latest(udid: &str) -> Result<Option<PairingEntry>, String> {
+    Ok(for_device(udid)?.into_iter().next())
+}
+
+/// The entry `id`, `None` if there is no such entry.
+pub fn get(id: &str) -> Result<Option<PairingEntry>, String> {
+    if !valid_id(id) {
+        return Ok(None);
+    }
+    let (json_path, _) = entry_paths(id)?;
+    if !json_path.exists() {
+        return Ok(None);
+    }
+    read_entry(&json_path).map(Some)
+}
+
+pub fn pairing_file(id: &str) -> Result<RpPairingFile, String> {
+    let (_, plist_path) = entry_paths(id)?;
+    read_pairing_file(&plist_path)
+}
+
+pub fn details(entry: PairingEntry) -> Result<PairingDetails, String> {
+    let pairing_file = pairing_file(&entry.id)?;
+    Ok(PairingDetails {
+        entry,
+        identifier: pairing_file.identifier().to_string(),
+        plist: String::from_utf8(pairing_file.to_bytes())
+            .map_err(|e| format!("invalid utf8: {}", e))?,
+    })
+}
+
+/// Write the pairing file of entry `id` to `destination`, in plaintext; see
+/// `write_plaintext`.
+pub fn export(id: &str, destination: &Path) -> Result<(), String> {
+    write_plaintext(destination, &pairing_file(id)?)
+}
+
+/// Write `pairing_file` out of the store, e.g. for the companion app to import.
+/// Nothing else can open a sealed file, so it is written in plaintext whether
+/// encryption is on or not, and holds the private keys anyone with the file can
+/// pair as this computer with. On Unix only the owner may read it.
+pub fn write_plaintext(destination: &Path, pairing_file: &RpPairingFile) -> Result<(), String> {
+    let failed = |e: std::io::Error| {
+        format!(
+            "failed to write pairing file to {}: {}",
+            destination.display(),
+            e
+        )
+    };
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(destination).map_err(failed)?;
+    // the mode only applies to a new file; narrow an existing one before any key is in it
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .map_err(failed)?;
+    }
+    std::io::Write::write_all(&mut file, &pairing_file.to_bytes()).map_err(failed)?;
+    log::warn!(
+        "pairing_store: wrote plaintext pairing file {} with private keys",
+        destination.display()
+    );
+    Ok(())
+}
+
+/// Record the outcome of uploading entry `id`; `Err` carries the failure message.
+pub fn record_upload(id: &str, result: Result<(), String>) -> Result<PairingEntry, String> {
+    let mut entry = get(id)?.ok_or_else(|| format!("no stored pairing {}", id))?;
+    match result {
+        Ok(()) => {
+            entry.upload_status = UploadStatus::Uploaded;
+            entry.uploaded_at = Some(unix_now());
+            entry.upload_error = None;
+        }
+        Err(message) => {
+            entry.upload_status = UploadStatus::Failed;
+            entry.upload_error = Some(message);
+        }
+    }
+    save(&entry)?;
+    Ok(entry)
+}
+
+pub fn delete(id: &str) -> Result<(), String> {
+    let (json_path, plist_path) = entry_paths(id)?;
+    if !json_path.exists() {
+        return Err(format!("no stored pairing {}", id));
+    }
+    for path in [plist_path, json_path] {
+        match std::fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("failed to delete {}: {}", path.display(), e)),
+        }
+    }
+    log::info!("pairing_store: deleted {}", id);
+    Ok(())
+}
+
+/// Rewrite every stored pairing file through `rewrite`, which gets and returns
+/// the bytes on disk. Used to encrypt or decrypt the store as a whole.
+pub fn rewrite_pairing_files(
+    rewrite: impl Fn(Vec<u8>) -> Result<Vec<u8>, String>,
+) -> Result<usize, String> {
+    let mut rewritten = 0;
+    for entry in list()? {
+        let (_, plist_path) = entry_paths(&entry.id)?;
+        let bytes = std::fs::read(&plist_path)
+            .map_err(|e| format!("failed to read {}: {}", plist_path.display(), e))?;
+        crate::replace_file(&plist_path, &rewrite(bytes)?)?;
+        rewritten += 1;
+    }
+    Ok(rewritten)
+}
+
+fn save(entry: &PairingEntry) -> Result<(), String> {
+    let (json_path, _) = entry_paths(&entry.id)?;
+    let json = serde_json::to_vec_pretty(entry)
+        .map_err(|e| format!("failed to serialize pairing entry: {}", e))?;
+    std::fs::write(&json_path, json)
+        .map_err(|e| format!("failed to write {}: {}", json_path.display(), e))
+}
+
+fn read_entry(path: &Path) -> Result<PairingEntry, String> {
+    let bytes =
+        std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    serde_json::from_slice(&bytes).map_err(|e| format!("malformed {}: {}", path.display(), e))
+}
+
+fn write_pairing_file(path: &Path, pairing_file: &RpPairingFile) -> Result<(), String> {
+    crate::replace_file(path, &vault::seal(pairing_file.to_bytes())?)
+}
+
+fn read_pairing_file(path: &Path) -> Result<RpPairingFile, String> {
+    let bytes =
+        std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let bytes = vault::open(bytes)?;
+    RpPairingFile::from_bytes(&bytes).map_err(|e| format!("idevice error: {:?}", e))
+}
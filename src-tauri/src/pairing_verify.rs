@@ -0,0 +1,174 @@
+//! Checking a pairing file the way the companion app will use it.
+//!
+//! The app reaches the device over the network, not through usbmuxd: it runs
+//! remote pairing verify against remoted, asks it for a tunnel listener,
+//! brings the TLS-PSK tunnel up and talks to RSD through it. This does the
+//! same from the host over Wi-Fi, so a file that passes here will work there.
+
+use std::{net::IpAddr, time::Duration};
+
+use idevice::{
+    remote_pairing::{
+        connect_tls_psk_tunnel_native, errors::RemotePairingError, RemotePairingClient,
+        RpPairingFile, RpPairingSocket,
+    },
+    rsd::RsdHandshake,
+    tcp::adapter::Adapter,
+    IdeviceError,
+};
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use serde::Serialize;
+use tokio::net::TcpStream;
+
+use crate::locator::{DeviceLocator, Route};
+
+/// Bonjour service remoted advertises remote pairing over the network under.
+const REMOTE_PAIRING_SERVICE: &str = "_remotepairing._tcp.local.";
+
+/// How long to listen for the device's advertisement.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Upper bound for the whole check, so an unresponsive device reads as unreachable.
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "result", rename_all = "camelCase")]
+pub enum PairingVerification {
+    /// Pair verify, tunnel and RSD handshake all went through.
+    Verified,
+    /// The device knows this host, but under newer keys than the file holds.
+    WrongKey,
+    /// The device no longer trusts this host.
+    Revoked,
+    /// The device couldn't be reached over Wi-Fi.
+    Unreachable { reason: String },
+}
+
+/// Verify `pairing_file` against `udid` over Wi-Fi.
+///
+/// `hostname` is the host name the file was paired under and `newest` the
+/// newest pairing stored for the device, used to tell a superseded file from a
+/// revoked one. Every full pair makes new keys, so the host identity's keys
+/// may belong to another device.
+pub async fn verify_pairing(
+    udid: &str,
+    hostname: &str,
+    pairing_file: &RpPairingFile,
+    newest: Option<&RpPairingFile>,
+) -> PairingVerification {
+    log::info!("verify_pairing: starting for udid={}", udid);
+
+    let result = tokio::time::timeout(VERIFY_TIMEOUT, async {
+        let device = DeviceLocator::locate(udid, Route::Network, "verify_pairing").await?;
+        let addr = device
+            .network_address()
+            .ok_or(IdeviceError::DeviceNotFound)?;
+        connect(udid, addr, hostname, pairing_file).await
+    })
+    .await;
+
+    let verification = match result {
+        Err(_) => PairingVerification::Unreachable {
+            reason: "timed out".to_string(),
+        },
+        Ok(Ok(())) => PairingVerification::Verified,
+        Ok(Err(IdeviceError::RemotePairing(
+            RemotePairingError::PairVerifyFailed | RemotePairingError::PairingRejected(_),
+        ))) => {
+            // The device answers both cases the same way. If the device was
+            // paired again since, under other keys with the same identifier,
+            // this file is simply out of date; otherwise the device dropped us.
+            let superseded = newest.is_some_and(|newest| {
+                newest.identifier() == pairing_file.identifier()
+                    && newest.public_key_bytes() != pairing_file.public_key_bytes()
+            });
+            if superseded {
+                PairingVerification::WrongKey
+            } else {
+                PairingVerification::Revoked
+            }
+        }
+        Ok(Err(IdeviceError::DeviceNotFound)) => PairingVerification::Unreachable {
+            reason: "the device is not on the network".to_string(),
+        },
+        // its Display drops the message
+        Ok(Err(IdeviceError::InternalError(reason))) => PairingVerification::Unreachable { reason },
+        Ok(Err(e)) => PairingVerification::Unreachable {
+            reason: e.to_string(),
+        },
+    };
+    log::info!("verify_pairing: {} {:?}", udid, verification);
+    verification
+}
+
+async fn connect(
+    udid: &str,
+    addr: IpAddr,
+    hostname: &str,
+    pairing_file: &RpPairingFile,
+) -> Result<(), IdeviceError> {
+    let port = advertised_port(addr).await?;
+    log::debug!("verify_pairing: connecting to remoted at {}:{}", addr, port);
+    let stream = TcpStream::connect((addr, port)).await?;
+
+    // Verify only; falling back to a full pair here would hide a revoked file
+    let mut pairing_file = pairing_file.clone();
+    let mut client =
+        RemotePairingClient::new(RpPairingSocket::new(stream), hostname, &mut pairing_file);
+    client.attempt_pair_verify().await?;
+    client.validate_pairing().await?;
+    log::debug!("verify_pairing: pair verify succeeded for {}", udid);
+
+    let port = client.create_tcp_listener().await?;
+    let stream = TcpStream::connect((addr, port)).await?;
+    let tunnel = connect_tls_psk_tunnel_native(stream, client.encryption_key()).await?;
+    log::debug!("verify_pairing: tunnel up: {:?}", tunnel.info);
+
+    let info = tunnel.info.clone();
+    let mut adapter = Adapter::new(
+        Box::new(tunnel.into_inner()),
+        info.client_address.parse::<IpAddr>()?,
+        info.server_address.parse::<IpAddr>()?,
+    );
+    adapter.set_mss((info.mtu as usize).saturating_sub(60));
+    let mut adapter = adapter.to_async_handle();
+
+    let handshake = RsdHandshake::new(adapter.connect(info.server_rsd_port).await?).await?;
+    log::debug!(
+        "verify_pairing: RSD handshake with {} offered {} services",
+        udid,
+        handshake.services.len()
+    );
+    Ok(())
+}
+
+/// The port the device at `addr` advertises remote pairing on.
+async fn advertised_port(addr: IpAddr) -> Result<u16, IdeviceError> {
+    let mdns = ServiceDaemon::new()
+        .map_err(|e| IdeviceError::InternalError(format!("mDNS unavailable: {}", e)))?;
+    let events = mdns
+        .browse(REMOTE_PAIRING_SERVICE)
+        .map_err(|e| IdeviceError::InternalError(format!("mDNS browse failed: {}", e)))?;
+
+    let found = tokio::time::timeout(DISCOVERY_TIMEOUT, async {
+        while let Ok(event) = events.recv_async().await {
+            if let ServiceEvent::ServiceResolved(info) = event {
+                if info.get_addresses().contains(&addr) {
+                    return Some(info.get_port());
+                }
+            }
+        }
+        None
+    })
+    .await
+    .ok()
+    .flatten();
+    let _ = mdns.shutdown();
+
+    found.ok_or_else(|| {
+        IdeviceError::InternalError(format!(
+            "{} does not advertise {}",
+            addr, REMOTE_PAIRING_SERVICE
+        ))
+    })
+}
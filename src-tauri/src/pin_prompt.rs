@@ -0,0 +1,106 @@
+//! Round trip to the frontend for the code a device shows while pairing.
+//!
+//! The backend emits `pairing-pin-request` and waits; the frontend answers
+//! with the `submit_pairing_pin` command, or cancels with a null PIN.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use serde::Serialize;
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::sync::oneshot;
+
+use crate::pairing;
+
+pub const PAIRING_PIN_REQUEST_EVENT: &str = "pairing-pin-request";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PinRequestEvent {
+    pub udid: String,
+    /// The prompt is abandoned after this many seconds.
+    pub timeout_secs: u64,
+}
+
+/// Sequence number of the prompt and where its answer goes.
+type Pending = (u64, oneshot::Sender<Option<String>>);
+
+/// Managed state holding the outstanding prompt for each device.
+#[derive(Default)]
+pub struct PinPrompts {
+    /// Each prompt is tagged with a sequence number so one that ended only
+    /// removes itself, not a newer prompt for the same device.
+    pending: Mutex<HashMap<String, Pending>>,
+    next: AtomicU64,
+}
+
+// Takes the prompt out of `PinPrompts` however the wait for it ends: answered,
+// timed out, or dropped with a cancelled operation
+struct PendingGuard<'a> {
+    prompts: &'a PinPrompts,
+    udid: &'a str,
+    seq: u64,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        let mut pending = self.prompts.pending.lock().unwrap();
+        if pending
+            .get(self.udid)
+            .is_some_and(|(seq, _)| *seq == self.seq)
+        {
+            pending.remove(self.udid);
+        }
+    }
+}
+
+/// Ask the frontend for the code shown on `udid`. `None` if the user cancelled.
+pub async fn request(app: &AppHandle, udid: &str) -> Option<String> {
+    let prompts = app.state::<PinPrompts>();
+    let seq = prompts.next.fetch_add(1, Ordering::Relaxed);
+    let (tx, rx) = oneshot::channel();
+    // A newer prompt for the same device supersedes (and so cancels) the old one
+    prompts
+        .pending
+        .lock()
+        .unwrap()
+        .insert(udid.to_string(), (seq, tx));
+    let _guard = PendingGuard {
+        prompts: &prompts,
+        udid,
+        seq,
+    };
+
+    let _ = app.emit(
+        PAIRING_PIN_REQUEST_EVENT,
+        PinRequestEvent {
+            udid: udid.to_string(),
+            timeout_secs: pairing::PIN_TIMEOUT.as_secs(),
+        },
+    );
+    rx.await.ok().flatten()
+}
+
+/// Answer the outstanding prompt for `udid`; `None` cancels it.
+pub fn submit(app: &AppHandle, udid: &str, pin: Option<String>) -> Result<(), String> {
+    if let Some(pin) = &pin {
+        if pin.len() != 6 || !pin.chars().all(|c| c.is_ascii_digit()) {
+            return Err("the pairing code is 6 digits".into());
+        }
+    }
+    let (_, tx) = app
+        .state::<PinPrompts>()
+        .pending
+        .lock()
+        .unwrap()
+        .remove(udid)
+        .ok_or_else(|| format!("no pairing code was requested for {}", udid))?;
+    // The pairing may have timed out in the meantime
+    tx.send(pin)
+        .map_err(|_| format!("pairing with {} is no longer waiting for a code", udid))
+}
@@ -0,0 +1,325 @@
+//! Readiness checklist run before setup.
+//!
+//! Every check runs even when an earlier one fails, so the frontend can show
+//! everything that stands between the device and a successful setup at once.
+
+use idevice::{lockdown::LockdownClient, IdeviceError, IdeviceService};
+use serde::Serialize;
+
+use crate::{
+    companion::{self, CompanionStatus},
+    device_info,
+    error::AppError,
+    idevice_helpers,
+    locator::DeviceLocator,
+    wifi_debugging,
+};
+
+/// RemotePairing (and the CoreDeviceProxy tunnel it runs over) needs iOS 17.
+pub const MIN_REMOTE_PAIRING_MAJOR: u64 = 17;
+
+/// Below this much free space the device is reported as too full.
+const MIN_FREE_BYTES: u64 = 50 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PreflightItem {
+    IosVersion,
+    Trusted,
+    Unlocked,
+    DeveloperMode,
+    CompanionApp,
+    CompanionVersion,
+    WifiDebugging,
+    FreeSpace,
+    HostPrerequisites,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+    /// Not blocking, but worth showing.
+    Warn,
+    /// Couldn't be checked, usually because the device doesn't trust us yet.
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PreflightCheck {
+    pub item: PreflightItem,
+    pub status: CheckStatus,
+    /// What was found, or why the check failed.
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PreflightReport {
+    pub udid: String,
+    /// No check failed and every blocking item could be checked.
+    pub ready: bool,
+    pub checks: Vec<PreflightCheck>,
+}
+
+impl PreflightItem {
+    /// Setup can't go ahead without knowing these pass.
+    fn is_blocking(self) -> bool {
+        matches!(
+            self,
+            Self::Trusted | Self::Unlocked | Self::DeveloperMode | Self::CompanionApp
+        )
+    }
+}
+
+fn check(
+    item: PreflightItem,
+    status: CheckStatus,
+    detail: impl Into<Option<String>>,
+) -> PreflightCheck {
+    PreflightCheck {
+        item,
+        status,
+        detail: detail.into(),
+    }
+}
+
+/// Run every readiness check against `device`.
+pub async fn preflight(device: &DeviceLocator) -> PreflightReport {
+    let udid = device.udid();
+    log::info!("preflight: starting for udid={}", udid);
+
+    let mut checks = vec![host_prerequisites()];
+    let unchecked = match device_checks(device, &mut checks).await {
+        Ok(()) => "device could not be queried".to_string(),
+        Err(e) => {
+            log::warn!("preflight: lockdown failed for {}: {:?}", udid, e);
+            format!("not checked: {}", AppError::from(e))
+        }
+    };
+
+    // Whatever couldn't be reached is still listed so the checklist is always complete
+    for item in [
+        PreflightItem::IosVersion,
+        PreflightItem::Trusted,
+        PreflightItem::Unlocked,
+        PreflightItem::DeveloperMode,
+        PreflightItem::CompanionApp,
+        PreflightItem::CompanionVersion,
+        PreflightItem::WifiDebugging,
+        PreflightItem::FreeSpace,
+    ] {
+        if !checks.iter().any(|c| c.item == item) {
+            checks.push(check(item, CheckStatus::Unknown, unchecked.clone()));
+        }
+    }
+
+    let ready = checks.iter().all(|c| match c.status {
+        CheckStatus::Fail => false,
+        CheckStatus::Unknown => !c.item.is_blocking(),
+        CheckStatus::Pass | CheckStatus::Warn => true,
+    });
+    log::info!("preflight: {} ready={}", udid, ready);
+    PreflightReport {
+        udid: udid.to_string(),
+        ready,
+        checks,
+    }
+}
+
+async fn device_checks(
+    device: &DeviceLocator,
+    checks: &mut Vec<PreflightCheck>,
+) -> Result<(), IdeviceError> {
+    let mut lc = LockdownClient::connect(device.provider()).await?;
+
+    // ProductVersion is readable without a session
+    let version = lc
+        .get_value(Some("ProductVersion"), None)
+        .await
+        .ok()
+        .and_then(|v| v.as_string().map(|v| v.to_string()));
+    checks.push(match &version {
+        Some(v) if device_info::ios_major_version(v) >= MIN_REMOTE_PAIRING_MAJOR => {
+            check(PreflightItem::IosVersion, CheckStatus::Pass, v.clone())
+        }
+        Some(v) => check(
+            PreflightItem::IosVersion,
+            CheckStatus::Fail,
+            format!(
+                "iOS {} is too old, iOS {} or later is required",
+                v, MIN_REMOTE_PAIRING_MAJOR
+            ),
+        ),
+        None => check(PreflightItem::IosVersion, CheckStatus::Unknown, None),
+    });
+
+    let session = match device.pair_record().await {
+        Ok(pairing_file) => lc.start_session(&pairing_file).await,
+        Err(e) => Err(e),
+    };
+    // Only a device that hasn't been unlocked since it booted refuses the
+    // session; once it has, lockdown takes sessions while it is locked again.
+    // Nothing lockdown offers tells the two apart (PasswordProtected only says
+    // a passcode is set), so a session leaves the lock state open.
+    match session {
+        Ok(()) => {
+            checks.push(check(PreflightItem::Trusted, CheckStatus::Pass, None));
+            checks.push(check(
+                PreflightItem::Unlocked,
+                CheckStatus::Warn,
+                "can't be checked; keep the device unlocked during setup".to_string(),
+            ));
+        }
+        Err(e @ (IdeviceError::PasswordProtected | IdeviceError::DeviceLocked)) => {
+            checks.push(check(PreflightItem::Trusted, CheckStatus::Unknown, None));
+            checks.push(check(
+                PreflightItem::Unlocked,
+                CheckStatus::Fail,
+                "unlock the device".to_string(),
+            ));
+            return Err(e);
+        }
+        Err(e) => {
+            log::debug!("preflight: no session with {}: {:?}", device.udid(), e);
+            checks.push(check(
+                PreflightItem::Trusted,
+                CheckStatus::Fail,
+                "the device does not trust this computer yet".to_string(),
+            ));
+            return Err(e);
+        }
+    }
+
+    let developer_mode = lc
+        .get_value(
+            Some("DeveloperModeStatus"),
+            Some("com.apple.security.mac.amfi"),
+        )
+        .await
+        .ok()
+        .and_then(|v| v.as_boolean());
+    checks.push(match developer_mode {
+        Some(true) => check(PreflightItem::DeveloperMode, CheckStatus::Pass, None),
+        Some(false) => check(
+            PreflightItem::DeveloperMode,
+            CheckStatus::Fail,
+            "developer mode is off".to_string(),
+        ),
+        None => check(PreflightItem::DeveloperMode, CheckStatus::Unknown, None),
+    });
+
+    // Setup turns it on itself unless told not to, so being off isn't blocking
+    let wifi_debugging = wifi_debugging::read(&mut lc).await.ok();
+    checks.push(match wifi_debugging {
+        Some(true) => check(PreflightItem::WifiDebugging, CheckStatus::Pass, None),
+        Some(false) => check(
+            PreflightItem::WifiDebugging,
+            CheckStatus::Warn,
+            "off; setup turns it on unless told to leave it alone".to_string(),
+        ),
+        None => check(PreflightItem::WifiDebugging, CheckStatus::Unknown, None),
+    });
+
+    let free = lc
+        .get_value(Some("AmountDataAvailable"), Some("com.apple.disk_usage"))
+        .await
+        .ok()
+        .and_then(|v| v.as_unsigned_integer());
+    checks.push(match free {
+        Some(free) if free >= MIN_FREE_BYTES => check(
+            PreflightItem::FreeSpace,
+            CheckStatus::Pass,
+            format!("{} MB free", free / (1024 * 1024)),
+        ),
+        Some(free) => check(
+            PreflightItem::FreeSpace,
+            CheckStatus::Fail,
+            format!("only {} MB free", free / (1024 * 1024)),
+        ),
+        None => check(PreflightItem::FreeSpace, CheckStatus::Unknown, None),
+    });
+
+    match companion::verify(device).await {
+        Ok(CompanionStatus::Ready { version }) => {
+            checks.push(check(PreflightItem::CompanionApp, CheckStatus::Pass, None));
+            checks.push(check(
+                PreflightItem::CompanionVersion,
+                CheckStatus::Pass,
+                version,
+            ));
+        }
+        Ok(CompanionStatus::WrongVersion { version, minimum }) => {
+            checks.push(check(PreflightItem::CompanionApp, CheckStatus::Pass, None));
+            checks.push(check(
+                PreflightItem::CompanionVersion,
+                CheckStatus::Fail,
+                format!(
+                    "{} is installed, {} or later is required",
+                    version.as_deref().unwrap_or("an unknown version"),
+                    minimum
+                ),
+            ));
+        }
+        Ok(CompanionStatus::ContainerNotVendable { version, reason }) => {
+            checks.push(check(
+                PreflightItem::CompanionApp,
+                CheckStatus::Fail,
+                format!("its Documents folder is not reachable: {}", reason),
+            ));
+            checks.push(check(
+                PreflightItem::CompanionVersion,
+                CheckStatus::Pass,
+                version,
+            ));
+        }
+        Ok(CompanionStatus::NotInstalled) => {
+            checks.push(check(
+                PreflightItem::CompanionApp,
+                CheckStatus::Fail,
+                "Auto Capture is not installed".to_string(),
+            ));
+            checks.push(check(
+                PreflightItem::CompanionVersion,
+                CheckStatus::Unknown,
+                None,
+            ));
+        }
+        Err(e) => {
+            log::warn!(
+                "preflight: installation_proxy failed for {}: {:?}",
+                device.udid(),
+                e
+            );
+            let detail = AppError::from(e).to_string();
+            checks.push(check(
+                PreflightItem::CompanionApp,
+                CheckStatus::Unknown,
+                detail.clone(),
+            ));
+            checks.push(check(
+                PreflightItem::CompanionVersion,
+                CheckStatus::Unknown,
+                detail,
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn host_prerequisites() -> PreflightCheck {
+    // usbmuxd is evidently reachable since the device was located; what's
+    // left is the Apple driver package on Windows
+    match idevice_helpers::check_apple_drivers() {
+        Ok(status) if status == "Missing" => check(
+            PreflightItem::HostPrerequisites,
+            CheckStatus::Fail,
+            "Apple device drivers are not installed".to_string(),
+        ),
+        Ok(_) => check(PreflightItem::HostPrerequisites, CheckStatus::Pass, None),
+        Err(e) => check(PreflightItem::HostPrerequisites, CheckStatus::Unknown, e),
+    }
+}
@@ -0,0 +1,51 @@
+//! User settings, persisted as JSON in the app data folder.
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::host_name::HostNameTemplate;
+
+const SETTINGS_FILE: &str = "settings.json";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Settings {
+    /// usbmuxd socket path or `host:port`; `None` uses the platform default.
+    pub usbmuxd_address: Option<String>,
+    /// What the host identity is named when it is created or rotated.
+    pub host_name: HostNameTemplate,
+}
+
+fn settings_path() -> Result<PathBuf, String> {
+    Ok(PathBuf::from(crate::app_data_folder()?).join(SETTINGS_FILE))
+}
+
+/// Read the settings file, falling back to defaults if it is missing or unreadable.
+pub fn load() -> Settings {
+    let path = match settings_path() {
+        Ok(path) => path,
+        Err(e) => {
+            log::warn!("settings: {}", e);
+            return Settings::default();
+        }
+    };
+    match std::fs::read(&path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+            log::warn!("settings: ignoring malformed {}: {}", path.display(), e);
+            Settings::default()
+        }),
+        Err(_) => Settings::default(),
+    }
+}
+
+pub fn save(settings: &Settings) -> Result<(), String> {
+    let path = settings_path()?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| format!("failed to create dir: {}", e))?;
+    }
+    let json = serde_json::to_vec_pretty(settings)
+        .map_err(|e| format!("failed to serialize settings: {}", e))?;
+    std::fs::write(&path, json)
+        .map_err(|e| format!("failed to write settings to {}: {}", path.display(), e))
+}
@@ -0,0 +1,129 @@
+//! Progress of `setup_device`, stage by stage.
+//!
+//! Setting a device up takes a while and fails in very different places, so
+//! every stage is reported as it starts, with its position and the time since
+//! setup began. The frontend draws a stepper from it and the log shows where
+//! a failed setup stopped.
+
+use std::{sync::Mutex, time::Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::operations::OperationStarted;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SetupStage {
+    /// Getting the device to trust this computer.
+    Trust,
+    /// Checking the companion app can receive the pairing file.
+    CompanionCheck,
+    /// Turning Wi-Fi debugging on, unless `SetupOptions` says to leave it.
+    WifiDebugging,
+    /// Bringing up the CoreDeviceProxy tunnel.
+    Tunnel,
+    RsdHandshake,
+    Pairing,
+    /// Waiting for the device to persist the pairing.
+    ConfirmCommit,
+    /// Opening the companion app's Documents folder through house_arrest.
+    VendDocuments,
+    /// Writing the pairing file over AFC.
+    WriteFile,
+}
+
+impl SetupStage {
+    pub const ALL: [SetupStage; 9] = [
+        SetupStage::Trust,
+        SetupStage::CompanionCheck,
+        SetupStage::WifiDebugging,
+        SetupStage::Tunnel,
+        SetupStage::RsdHandshake,
+        SetupStage::Pairing,
+        SetupStage::ConfirmCommit,
+        SetupStage::VendDocuments,
+        SetupStage::WriteFile,
+    ];
+
+    /// 0-based position in `ALL`.
+    pub fn index(self) -> usize {
+        Self::ALL.iter().position(|s| *s == self).unwrap_or(0)
+    }
+}
+
+/// How `setup_device` treats the device beyond pairing it.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SetupOptions {
+    /// Leave Wi-Fi debugging as it is, for devices where MDM manages it.
+    pub skip_wifi_debugging: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetupProgress {
+    pub udid: String,
+    pub stage: SetupStage,
+    pub index: usize,
+    pub total: usize,
+    /// Milliseconds since setup started.
+    pub elapsed_ms: u64,
+}
+
+/// Sent on `setup_device`'s channel.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum SetupEvent {
+    /// Always first: the operation id to cancel setup with.
+    Started(OperationStarted),
+    Progress(SetupProgress),
+}
+
+/// Turns stage changes into `SetupProgress` for `on_progress` and remembers
+/// the current stage for the log if setup fails.
+pub struct SetupReporter<F> {
+    udid: String,
+    started: Instant,
+    current: Mutex<Option<SetupStage>>,
+    on_progress: F,
+}
+
+impl<F: Fn(SetupProgress)> SetupReporter<F> {
+    pub fn new(udid: &str, on_progress: F) -> Self {
+        Self {
+            udid: udid.to_string(),
+            started: Instant::now(),
+            current: Mutex::new(None),
+            on_progress,
+        }
+    }
+
+    pub fn stage(&self, stage: SetupStage) {
+        *self.current.lock().unwrap() = Some(stage);
+        let elapsed = self.started.elapsed();
+        log::info!(
+            "setup_device: {} stage {:?} ({}/{}) at {:?}",
+            self.udid,
+            stage,
+            stage.index() + 1,
+            SetupStage::ALL.len(),
+            elapsed
+        );
+        (self.on_progress)(SetupProgress {
+            udid: self.udid.clone(),
+            stage,
+            index: stage.index(),
+            total: SetupStage::ALL.len(),
+            elapsed_ms: elapsed.as_millis() as u64,
+        });
+    }
+
+    /// The stage setup is in, or stopped at.
+    pub fn current(&self) -> Option<SetupStage> {
+        *self.current.lock().unwrap()
+    }
+
+    pub fn elapsed(&self) -> std::time::Duration {
+        self.started.elapsed()
+    }
+}
@@ -0,0 +1,156 @@
+//! Lockdown trust handling: detects whether this computer is trusted by a
+//! device and, when it isn't, runs the "Trust This Computer" pairing flow.
+
+use std::time::{Duration, Instant};
+
+use idevice::{lockdown::LockdownClient, pairing_file::PairingFile, IdeviceError, IdeviceService};
+use serde::Serialize;
+
+use crate::{error::AppError, locator::DeviceLocator, muxer};
+
+/// Event emitted whenever the user needs to act on the device.
+pub const TRUST_PROMPT_EVENT: &str = "trust-prompt";
+
+/// How long the user gets to unlock the device and tap Trust.
+pub const TRUST_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Host name shown on the device in the trust dialog.
+const TRUST_HOST_NAME: &str = "Auto Capture Pair";
+
+/// If pairing hasn't answered after this long, the trust dialog is on screen.
+const DIALOG_GRACE: Duration = Duration::from_millis(1500);
+
+/// Delay between retries while the device is locked or the record isn't visible yet.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the user currently needs to do on the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TrustPrompt {
+    /// The trust dialog is on screen; tap Trust and enter the passcode.
+    AwaitingTrust,
+    /// The device must be unlocked before it will show the trust dialog.
+    Locked,
+    /// The pair record was accepted and saved into usbmuxd.
+    Trusted,
+    /// The user tapped Don't Trust.
+    Denied,
+    /// Nothing happened on the device within `TRUST_TIMEOUT`.
+    TimedOut,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrustPromptEvent {
+    pub udid: String,
+    pub prompt: TrustPrompt,
+}
+
+/// Make sure usbmuxd holds a pair record for `device` that the device accepts,
+/// pairing over lockdown if needed. The trust dialog is only offered over USB,
+/// so `device` must be located with `Route::Usb`.
+///
+/// `on_prompt` is called whenever the user needs to do something different on
+/// the device. It is not called at all when the device already trusts us.
+pub async fn ensure_trusted(
+    device: &DeviceLocator,
+    timeout: Duration,
+    on_prompt: impl Fn(TrustPrompt),
+) -> Result<PairingFile, AppError> {
+    let udid = device.udid();
+    log::info!("ensure_trusted: starting for udid={}", udid);
+    let provider = device.provider();
+
+    if let Ok(pairing_file) = device.pair_record().await {
+        let mut lc = LockdownClient::connect(provider).await?;
+        match lc.start_session(&pairing_file).await {
+            Ok(()) => {
+                log::debug!("ensure_trusted: {} already trusts this computer", udid);
+                return Ok(pairing_file);
+            }
+            Err(IdeviceError::InvalidHostID) => {
+                log::info!(
+                    "ensure_trusted: stored pair record for {} was rejected",
+                    udid
+                );
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let mut uc = muxer::connect().await?;
+    let system_buid = uc.get_buid().await?;
+    let host_id = uuid::Uuid::new_v4().to_string().to_uppercase();
+    let deadline = Instant::now() + timeout;
+
+    let mut last_prompt = None;
+    let prompt = |p: TrustPrompt, last: &mut Option<TrustPrompt>| {
+        if *last != Some(p) {
+            *last = Some(p);
+            on_prompt(p);
+        }
+    };
+
+    let mut pairing_file = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let mut lc = LockdownClient::connect(provider).await?;
+        let mut pair = Box::pin(lc.pair(&host_id, &system_buid, Some(TRUST_HOST_NAME)));
+        // pair() answers straight away when the device is locked and otherwise keeps
+        // polling while the dialog is up, so the deadline covers the user too
+        let result = tokio::time::timeout(remaining, async {
+            match tokio::time::timeout(DIALOG_GRACE, &mut pair).await {
+                Ok(result) => result,
+                Err(_) => {
+                    prompt(TrustPrompt::AwaitingTrust, &mut last_prompt);
+                    pair.await
+                }
+            }
+        })
+        .await;
+        match result {
+            Ok(Ok(pairing_file)) => break pairing_file,
+            Ok(Err(IdeviceError::PasswordProtected)) => {
+                prompt(TrustPrompt::Locked, &mut last_prompt);
+                if Instant::now() + POLL_INTERVAL >= deadline {
+                    on_prompt(TrustPrompt::TimedOut);
+                    return Err(trust_timeout());
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+            Ok(Err(IdeviceError::UserDeniedPairing)) => {
+                on_prompt(TrustPrompt::Denied);
+                return Err(IdeviceError::UserDeniedPairing.into());
+            }
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => {
+                on_prompt(TrustPrompt::TimedOut);
+                return Err(trust_timeout());
+            }
+        }
+    };
+    log::info!("ensure_trusted: {} accepted the pairing request", udid);
+
+    pairing_file.udid = Some(udid.to_string());
+    uc.save_pair_record(udid, pairing_file.clone().serialize()?)
+        .await?;
+
+    // usbmuxd writes the record asynchronously; don't report success until it reads back
+    loop {
+        if device.pair_record().await.is_ok() {
+            break;
+        }
+        if Instant::now() >= deadline {
+            on_prompt(TrustPrompt::TimedOut);
+            return Err(trust_timeout());
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    log::info!("ensure_trusted: saved pair record for {}", udid);
+    on_prompt(TrustPrompt::Trusted);
+    Ok(pairing_file)
+}
+
+fn trust_timeout() -> AppError {
+    AppError::Timeout("waiting for the device to trust this computer".into())
+}
@@ -0,0 +1,378 @@
+//! At-rest encryption of the pairing files in the app data folder.
+//!
+//! Pairing files hold private keys that give lasting access to the device, so
+//! the pairing store and the host identity can be kept encrypted. The key
+//! lives in the OS secret store (Keychain, Credential Manager, Secret Service
+//! on Linux); where there is none it is derived from a passphrase instead, and
+//! the store stays locked until the passphrase is entered.
+//!
+//! Encryption is on while `pairing_key.json` exists. Sealed files start with
+//! `MAGIC`; `open` passes anything else through, so plaintext files written
+//! before encryption was turned on stay readable.
+
+use std::{path::PathBuf, sync::Mutex};
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::error::AppError;
+
+const KEY_FILE: &str = "pairing_key.json";
+const KEYRING_SERVICE: &str = "Auto Capture Pair";
+const KEYRING_USER: &str = "pairing-store";
+
+const MAGIC: &[u8] = b"ACPSEAL1";
+const NONCE_LEN: usize = 12;
+const SALT_LEN: usize = 16;
+/// Sealed into the key file so a wrong passphrase is caught on unlock.
+const CHECK: &[u8] = b"auto-capture-pair";
+
+// The key once read from the secret store or derived from the passphrase
+static KEY: Mutex<Option<Key>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum KeySource {
+    SecretStore,
+    Passphrase,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeyFile {
+    source: KeySource,
+    /// Argon2 salt, for `KeySource::Passphrase`.
+    salt: Option<Vec<u8>>,
+    check: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VaultStatus {
+    pub enabled: bool,
+    pub source: Option<KeySource>,
+    /// The key is at hand, so pairing files can be read and written.
+    pub unlocked: bool,
+}
+
+fn key_file_path() -> Result<PathBuf, String> {
+    Ok(PathBuf::from(crate::app_data_folder()?).join(KEY_FILE))
+}
+
+fn read_key_file() -> Result<Option<KeyFile>, String> {
+    let path = key_file_path()?;
+    match std::fs::read(&path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|e| format!("malformed {}: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("failed to read {}: {}", path.display(), e)),
+    }
+}
+
+fn write_key_file(key_file: &KeyFile) -> Result<(), String> {
+    let json = serde_json::to_vec_pretty(key_file)
+        .map_err(|e| format!("failed to serialize key file: {}", e))?;
+    let path = key_file_path()?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| format!("failed to create dir: {}", e))?;
+    }
+    std::fs::write(&path, json).map_err(|e| format!("failed to write {}: {}", path.display(), e))
+}
+
+fn keyring_entry() -> Result<keyring::Entry, AppError> {
+    keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER)
+        .map_err(|e| AppError::SecretStore(e.to_string()))
+}
+
+/// The key, `None` while encryption is off. Fails while a passphrase store
+/// is locked or the secret store won't hand the key out.
+fn key() -> Result<Option<Key>, AppError> {
+    let mut cached = KEY.lock().unwrap();
+    let Some(key_file) = read_key_file().map_err(AppError::Io)? else {
+        return Ok(None);
+    };
+    if let Some(key) = *cached {
+        return Ok(Some(key));
+    }
+    match key_file.source {
+        KeySource::Passphrase => Err(AppError::StoreLocked),
+        KeySource::SecretStore => {
+            let secret = keyring_entry()?.get_secret().map_err(|e| {
+                AppError::SecretStore(format!("could not read the pairing store key: {}", e))
+            })?;
+            if secret.len() != 32 {
+                return Err(AppError::SecretStore(
+                    "it holds a malformed pairing store key".into(),
+                ));
+            }
+            let key = *Key::from_slice(&secret);
+            *cached = Some(key);
+            Ok(Some(key))
+        }
+    }
+}
+
+pub fn status() -> Result<VaultStatus, AppError> {
+    let key_file = read_key_file().map_err(AppError::Io)?;
+    Ok(VaultStatus {
+        enabled: key_file.is_some(),
+        source: key_file.map(|f| f.source),
+        unlocked: key().is_ok(),
+    })
+}
+
+/// Encryption is on but the key isn't at hand.
+pub fn is_locked() -> bool {
+    key().is_err()
+}
+
+/// Encrypt `plain` for writing to disk; returned as is while encryption is off.
+pub fn seal(plain: Vec<u8>) -> Result<Vec<u8>, String> {
+    match key().map_err(|e| e.to_string())? {
+        Some(key) => seal_with(&key, &plain),
+        None => Ok(plain),
+    }
+}
+
+/// Decrypt what `seal` wrote. Plaintext is returned as is.
+pub fn open(data: Vec<u8>) -> Result<Vec<u8>, String> {
+    if !data.starts_with(MAGIC) {
+        return Ok(data);
+    }
+    let key = key()
+        .map_err(|e| e.to_string())?
+        .ok_or("the file is encrypted but the pairing store key is gone")?;
+    open_with(&key, &data)
+}
+
+fn seal_with(key: &Key, plain: &[u8]) -> Result<Vec<u8>, String> {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let sealed = ChaCha20Poly1305::new(key)
+        .encrypt(&nonce, plain)
+        .map_err(|_| "encryption failed".to_string())?;
+    Ok([MAGIC, nonce.as_slice(), &sealed].concat())
+}
+
+fn open_with(key: &Key, data: &[u8]) -> Result<Vec<u8>, String> {
+    let data = data
+        .strip_prefix(MAGIC)
+        .filter(|data| data.len() >= NONCE_LEN)
+        .ok_or("the encrypted file is truncated")?;
+    let (nonce, sealed) = data.split_at(NONCE_LEN);
+    ChaCha20Poly1305::new(key)
+        .decrypt(Nonce::from_slice(nonce), sealed)
+        .map_err(|_| "the file does not decrypt with the pairing store key".to_string())
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key, String> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("failed to derive the key: {}", e))?;
+    Ok(key)
+}
+
+/// Turn encryption on with a new key, kept in the secret store or, where
+/// that isn't available, derived from `passphrase`. Existing files have to be
+/// rewritten through `seal` afterwards; if that fails, open what was already
+/// sealed again and `disable`, so encryption isn't left half on.
+pub fn enable(passphrase: Option<&str>) -> Result<KeySource, AppError> {
+    let mut cached = KEY.lock().unwrap();
+    if read_key_file().map_err(AppError::Io)?.is_some() {
+        return Err(AppError::InvalidInput(
+            "pairing files are already encrypted".into(),
+        ));
+    }
+
+    let stored = ChaCha20Poly1305::generate_key(&mut OsRng);
+    let (key, source, salt) = match keyring_entry().and_then(|entry| {
+        entry
+            .set_secret(stored.as_slice())
+            .map_err(|e| AppError::SecretStore(e.to_string()))
+    }) {
+        Ok(()) => (stored, KeySource::SecretStore, None),
+        Err(e) => {
+            let Some(passphrase) = passphrase.filter(|p| !p.is_empty()) else {
+                return Err(e);
+            };
+            log::warn!("vault: {}, falling back to a passphrase", e);
+            let mut salt = vec![0; SALT_LEN];
+            OsRng.fill_bytes(&mut salt);
+            (
+                derive_key(passphrase, &salt).map_err(AppError::Other)?,
+                KeySource::Passphrase,
+                Some(salt),
+            )
+        }
+    };
+
+    let key_file = KeyFile {
+        source,
+        salt,
+        check: seal_with(&key, CHECK).map_err(AppError::Other)?,
+    };
+    if let Err(e) = write_key_file(&key_file) {
+        if source == KeySource::SecretStore {
+            forget_secret();
+        }
+        return Err(AppError::Io(e));
+    }
+    *cached = Some(key);
+    log::info!("vault: encryption enabled with {:?}", source);
+    Ok(source)
+}
+
+/// Check `passphrase` against the key file and keep the key for this session.
+pub fn unlock(passphrase: &str) -> Result<(), AppError> {
+    let mut cached = KEY.lock().unwrap();
+    let key_file = read_key_file()
+        .map_err(AppError::Io)?
+        .ok_or_else(|| AppError::InvalidInput("pairing files are not encrypted".into()))?;
+    *cached = Some(passphrase_key(&key_file, passphrase)?);
+    log::info!("vault: unlocked");
+    Ok(())
+}
+
+/// The key `passphrase` derives for `key_file`, if it is the right one.
+fn passphrase_key(key_file: &KeyFile, passphrase: &str) -> Result<Key, AppError> {
+    let salt = match (key_file.source, &key_file.salt) {
+        (KeySource::Passphrase, Some(salt)) => salt,
+        _ => {
+            return Err(AppError::InvalidInput(
+                "the pairing store key is not passphrase protected".into(),
+            ))
+        }
+    };
+    let key = derive_key(passphrase, salt).map_err(AppError::Other)?;
+    if open_with(&key, &key_file.check).ok().as_deref() != Some(CHECK) {
+        return Err(AppError::InvalidInput("wrong passphrase".into()));
+    }
+    Ok(key)
+}
+
+/// Forget the key and turn encryption off. Files have to be rewritten through
+/// `open` before, while the key is still around.
+pub fn disable() -> Result<(), AppError> {
+    let mut cached = KEY.lock().unwrap();
+    let Some(key_file) = read_key_file().map_err(AppError::Io)? else {
+        return Ok(());
+    };
+    let path = key_file_path().map_err(AppError::Io)?;
+    std::fs::remove_file(&path)
+        .map_err(|e| AppError::Io(format!("failed to delete {}: {}", path.display(), e)))?;
+    if key_file.source == KeySource::SecretStore {
+        forget_secret();
+    }
+    *cached = None;
+    log::info!("vault: encryption disabled");
+    Ok(())
+}
+
+/// Remove the key from the secret store. Left behind it does no harm, so
+/// failing to is only logged.
+fn forget_secret() {
+    if let Err(e) = keyring_entry().and_then(|entry| {
+        entry
+            .delete_credential()
+            .map_err(|e| AppError::SecretStore(e.to_string()))
+    }) {
+        log::warn!(
+            "vault: could not remove the key from the secret store: {}",
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLIST: &[u8] = b"<?xml version=\"1.0\"?><plist><dict/></plist>";
+
+    #[test]
+    fn seal_round_trip() {
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        for plain in [PLIST, b"", MAGIC] {
+            let sealed = seal_with(&key, plain).unwrap();
+            assert!(sealed.starts_with(MAGIC));
+            assert_ne!(&sealed[MAGIC.len()..], plain);
+            assert_eq!(open_with(&key, &sealed).unwrap(), plain);
+        }
+        // a fresh nonce every time
+        assert_ne!(
+            seal_with(&key, PLIST).unwrap(),
+            seal_with(&key, PLIST).unwrap()
+        );
+    }
+
+    #[test]
+    fn wrong_key_or_tampering() {
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let sealed = seal_with(&key, PLIST).unwrap();
+        let other = ChaCha20Poly1305::generate_key(&mut OsRng);
+        assert!(open_with(&other, &sealed).is_err());
+
+        for i in MAGIC.len()..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 1;
+            assert!(open_with(&key, &tampered).is_err(), "byte {} flipped", i);
+        }
+    }
+
+    #[test]
+    fn truncated_input() {
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let sealed = seal_with(&key, PLIST).unwrap();
+        for len in 0..sealed.len() {
+            assert!(open_with(&key, &sealed[..len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn plaintext_passes_through() {
+        for plain in [PLIST, b"", &MAGIC[..MAGIC.len() - 1]] {
+            assert_eq!(open(plain.to_vec()).unwrap(), plain);
+        }
+    }
+
+    #[test]
+    fn passphrase_unlock() {
+        let salt = vec![7; SALT_LEN];
+        let key = derive_key("correct horse", &salt).unwrap();
+        let key_file = KeyFile {
+            source: KeySource::Passphrase,
+            salt: Some(salt),
+            check: seal_with(&key, CHECK).unwrap(),
+        };
+        assert_eq!(passphrase_key(&key_file, "correct horse").unwrap(), key);
+        for wrong in ["correct horse ", "Correct horse", ""] {
+            assert!(
+                matches!(
+                    passphrase_key(&key_file, wrong),
+                    Err(AppError::InvalidInput(ref m)) if m == "wrong passphrase"
+                ),
+                "{:?}",
+                wrong
+            );
+        }
+
+        // a check value that was cut short reads as a wrong passphrase, not a panic
+        let cut = KeyFile {
+            check: key_file.check[..4].to_vec(),
+            ..key_file.clone()
+        };
+        assert!(passphrase_key(&cut, "correct horse").is_err());
+
+        let secret_store = KeyFile {
+            source: KeySource::SecretStore,
+            salt: None,
+            ..key_file
+        };
+        assert!(passphrase_key(&secret_store, "correct horse").is_err());
+    }
+}
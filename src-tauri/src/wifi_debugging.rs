@@ -0,0 +1,54 @@
+//! Wi-Fi debugging, the `EnableWifiDebugging` key of the
+//! `com.apple.mobile.wireless_lockdown` domain.
+//!
+//! The device only advertises itself to usbmuxd over the network while it is
+//! on, which the companion app needs to reach it without a cable. Devices
+//! managed through MDM may have it set by policy, so setup can leave it alone.
+
+use idevice::{lockdown::LockdownClient, IdeviceError, IdeviceService};
+
+use crate::{error::AppError, locator::DeviceLocator};
+
+const DOMAIN: &str = "com.apple.mobile.wireless_lockdown";
+const KEY: &str = "EnableWifiDebugging";
+
+/// Read the setting over an existing lockdown connection. Needs a session.
+pub async fn read(lc: &mut LockdownClient) -> Result<bool, IdeviceError> {
+    let value = lc.get_value(Some(KEY), Some(DOMAIN)).await?;
+    value.as_boolean().ok_or_else(|| {
+        IdeviceError::UnexpectedResponse(format!("{} is not a boolean: {:?}", KEY, value))
+    })
+}
+
+/// Whether Wi-Fi debugging is on.
+pub async fn is_enabled(device: &DeviceLocator) -> Result<bool, IdeviceError> {
+    log::info!("is_enabled: starting for udid={}", device.udid());
+    let mut lc = session(device).await?;
+    read(&mut lc).await
+}
+
+/// Turn Wi-Fi debugging on or off and read it back, since lockdown accepts
+/// the write even when a profile keeps the old value.
+pub async fn set_enabled(device: &DeviceLocator, enabled: bool) -> Result<(), AppError> {
+    let udid = device.udid();
+    log::info!("set_enabled: {} wifi debugging for udid={}", enabled, udid);
+    let mut lc = session(device).await?;
+    lc.set_value(KEY, enabled.into(), Some(DOMAIN)).await?;
+
+    let now = read(&mut lc).await?;
+    if now != enabled {
+        log::warn!("set_enabled: {} kept {}={}", udid, KEY, now);
+        return Err(AppError::ManagedSetting(format!(
+            "the device kept Wi-Fi debugging {}, it may be managed by a profile",
+            if now { "on" } else { "off" }
+        )));
+    }
+    Ok(())
+}
+
+async fn session(device: &DeviceLocator) -> Result<LockdownClient, IdeviceError> {
+    let pairing_file = device.pair_record().await?;
+    let mut lc = LockdownClient::connect(device.provider()).await?;
+    lc.start_session(&pairing_file).await?;
+    Ok(lc)
+}
@@ -18,9 +18,11 @@ import {
 	Grid,
 	IconButton,
 	InputLabel,
+	LinearProgress,
 	Link,
 	MenuItem,
 	Select,
+	TextField,
 	Tooltip,
 	Typography,
 	useMediaQuery,
@@ -31,8 +33,17 @@ import { enqueueSnackbar } from "notistack";
 
 export default function HomePage() {
 	const [udid, setUDID] = React.useState("");
-	const [devices, setDevices] = React.useState({});
+	const [devices, setDevices] = React.useState([]);
 	const [devModeDialogOpen, setDevModeDialogOpen] = React.useState(false);
+	// Device waiting for the pairing code it shows, or null
+	const [pinRequest, setPinRequest] = React.useState(null);
+	const [pin, setPin] = React.useState("");
+	// Latest setup_device stage report while setup runs, or null
+	const [setupProgress, setSetupProgress] = React.useState(null);
+	// Ids of the cancellable backend operations this page started that still run
+	const [runningOperations, setRunningOperations] = React.useState([]);
+	const operationStarted = (operationId) =>
+		setRunningOperations((ids) => [...ids, operationId]);
 
 	const prefersDarkMode = useMediaQuery("(prefers-color-scheme: dark)");
 	const darkMode = useMediaQuery("(prefers-color-scheme: dark)")
@@ -74,37 +85,252 @@ export default function HomePage() {
 	);
 
 	let invoke = window.__TAURI__.core.invoke;
+	// Invokes a cancellable command that reports nothing but its operation id
+	async function invokeOperation(command, args) {
+		const onStart = new window.__TAURI__.core.Channel();
+		onStart.onmessage = (started) => operationStarted(started.operationId);
+		return await invoke(command, { ...args, onStart });
+	}
 	async function getDevices() {
 		return await invoke("get_devices");
 	}
 	window.getDevices = getDevices;
 	window.generatePairingFile = async (udid, filePath) => {
-		const debug = await invoke("generate_pairing_file", {
+		const debug = await invokeOperation("generate_pairing_file", {
 			udid,
 			destination: filePath,
 		});
 		return debug;
 	};
 
+	// Preflight items setupDevice takes care of: trust through pair_device,
+	// the others in the steps after it
+	const handledBySetup = ["trusted", "developerMode", "wifiDebugging"];
+	const preflightLabels = {
+		iosVersion: "iOS version",
+		trusted: "Trust",
+		unlocked: "Unlocked",
+		developerMode: "Developer Mode",
+		companionApp: "Auto Capture app",
+		companionVersion: "Auto Capture version",
+		wifiDebugging: "Wi-Fi debugging",
+		freeSpace: "Free space",
+		hostPrerequisites: "Computer setup",
+	};
+
+	const setupStageLabels = {
+		trust: "Waiting for the device to trust this computer",
+		companionCheck: "Checking the Auto Capture app",
+		wifiDebugging: "Turning on Wi-Fi debugging",
+		tunnel: "Opening a tunnel to the device",
+		rsdHandshake: "Looking up device services",
+		pairing: "Pairing",
+		confirmCommit: "Waiting for the device to save the pairing",
+		vendDocuments: "Opening the Auto Capture app's files",
+		writeFile: "Copying the pairing file",
+	};
+
 	async function setupDevice(udid) {
+		const report = await invoke("preflight", { udid });
+		const blockers = report.checks.filter(
+			(check) =>
+				check.status === "fail" && !handledBySetup.includes(check.item)
+		);
+		if (blockers.length > 0) {
+			throw new Error(
+				blockers
+					.map(
+						(check) =>
+							`${preflightLabels[check.item]}: ${check.detail ?? "not ready"}`
+					)
+					.join("; ")
+			);
+		}
+
+		// The developer mode calls below already need the device to trust this
+		// computer, so setup's own trust step would come too late
+		const trusted = report.checks.find((check) => check.item === "trusted");
+		if (trusted?.status !== "pass") {
+			await invokeOperation("pair_device", { udid });
+		}
+
 		const devModeEnabled = await invoke("get_device_in_dev_mode", { udid });
 		if (!devModeEnabled) {
-			await invoke("reveal_dev_mode", { udid });
+			// Progress arrives as developer-mode-stage events while this runs
 			setDevModeDialogOpen(true);
-			return;
+			try {
+				await invokeOperation("enable_developer_mode", { udid });
+			} finally {
+				setDevModeDialogOpen(false);
+			}
 		}
 
-		const debug = await invoke("setup_device", { udid });
-		return debug;
+		const onProgress = new window.__TAURI__.core.Channel();
+		onProgress.onmessage = (event) =>
+			event.type === "started"
+				? operationStarted(event.operationId)
+				: setSetupProgress(event);
+		let companion;
+		try {
+			companion = await invoke("setup_device", { udid, onProgress });
+		} finally {
+			setSetupProgress(null);
+		}
+		switch (companion.status) {
+			case "ready":
+				return companion;
+			case "notInstalled":
+				throw new Error("Install the Auto Capture app on your device first.");
+			case "wrongVersion":
+				throw new Error(
+					`Update the Auto Capture app (installed ${companion.version ?? "unknown"}, ${companion.minimum} or later required).`
+				);
+			default:
+				throw new Error(
+					`The Auto Capture app cannot receive the pairing file: ${companion.reason}`
+				);
+		}
+	}
+
+	// Sets up every USB device at once; results arrive per device on the channel
+	async function setupAllDevices() {
+		const udids = devices
+			.filter((device) => device.connectionType !== "network")
+			.map((device) => device.udid);
+		const onEvent = new window.__TAURI__.core.Channel();
+		onEvent.onmessage = (event) => {
+			if (event.type === "started") operationStarted(event.operationId);
+			if (event.type !== "finished") return;
+			const name =
+				devices.find((device) => device.udid === event.udid)?.name ??
+				event.udid;
+			const messages = {
+				succeeded: ["success", "set up"],
+				notReady: ["warning", "needs the Auto Capture app installed or updated"],
+				failed: ["error", `failed: ${event.error?.message}`],
+				cancelled: ["info", "cancelled"],
+			};
+			const [variant, message] = messages[event.status];
+			enqueueSnackbar(`${name} ${message}`, { variant });
+		};
+		const summary = await invoke("setup_devices", { udids, onEvent });
+		enqueueSnackbar(
+			`${summary.succeeded} of ${summary.total} devices set up` +
+				(summary.failed > 0 ? `, ${summary.failed} failed` : ""),
+			{ variant: summary.succeeded === summary.total ? "success" : "warning" }
+		);
+	}
+
+	// null cancels pairing on the backend
+	async function submitPin(value) {
+		const request = pinRequest;
+		setPinRequest(null);
+		setPin("");
+		try {
+			await invoke("submit_pairing_pin", { udid: request.udid, pin: value });
+		} catch (e) {
+			console.error("Error submitting pairing code:", e);
+		}
 	}
 
 	const fetchDevices = async () => {
-		const devices = await window.getDevices();
+		const devices = await invoke("subscribe_devices");
 		setDevices(devices);
 	};
 
 	React.useEffect(() => {
+		const listen = window.__TAURI__.event.listen;
+		// Replace (or add) a device in the list, keyed by UDID
+		const upsertDevice = (event) => {
+			setDevices((devices) => [
+				...devices.filter((d) => d.udid !== event.payload.udid),
+				event.payload,
+			]);
+		};
+		const removeDevice = (event) => {
+			setDevices((devices) =>
+				devices.filter((d) => d.udid !== event.payload.udid)
+			);
+			setUDID((udid) => (udid === event.payload.udid ? "" : udid));
+		};
+
+		// Tell the user what to do on the device while the backend pairs
+		const trustMessages = {
+			awaitingTrust:
+				'Tap "Trust" on your device and enter your passcode when asked.',
+			locked: "Unlock your device so it can show the trust prompt.",
+			trusted: "Your device now trusts this computer.",
+			denied: "Pairing was refused on the device.",
+			timedOut: "Timed out waiting for the device to trust this computer.",
+		};
+		const showTrustPrompt = (event) => {
+			const { prompt } = event.payload;
+			enqueueSnackbar(trustMessages[prompt], {
+				variant:
+					prompt === "trusted"
+						? "success"
+						: prompt === "denied" || prompt === "timedOut"
+							? "error"
+							: "info",
+			});
+		};
+
+		// Walk the user through developer mode while the backend enables it
+		const devModeMessages = {
+			revealed: "Developer Mode is now available in Settings.",
+			awaitingReboot: "Your device is restarting to turn on Developer Mode.",
+			awaitingConfirmation:
+				'Unlock your device and tap "Turn On" to confirm Developer Mode.',
+			enabled: "Developer Mode is on.",
+			blockedByPasscode:
+				"Your device has a passcode, so turn on Developer Mode yourself in Settings > Privacy & Security > Developer Mode.",
+		};
+		const showDevModeStage = (event) => {
+			const { stage } = event.payload;
+			enqueueSnackbar(devModeMessages[stage], {
+				variant: stage === "enabled" ? "success" : "info",
+			});
+		};
+
+		const trackOperation = (event) => {
+			const operation = event.payload;
+			if (operation.status === "running") return;
+			setRunningOperations((ids) =>
+				ids.filter((id) => id !== operation.operationId)
+			);
+			// a cancelled pairing no longer wants its code
+			setPinRequest((request) =>
+				request?.udid === operation.udid ? null : request
+			);
+		};
+
+		// The backend gives up after timeoutSecs, so stop asking by then too
+		let pinTimer;
+		const showPinPrompt = (event) => {
+			setPin("");
+			setPinRequest(event.payload);
+			clearTimeout(pinTimer);
+			pinTimer = setTimeout(
+				() => setPinRequest(null),
+				event.payload.timeoutSecs * 1000
+			);
+		};
+
+		const unlisteners = Promise.all([
+			listen("device-attached", upsertDevice),
+			listen("device-updated", upsertDevice),
+			listen("device-detached", removeDevice),
+			listen("trust-prompt", showTrustPrompt),
+			listen("developer-mode-stage", showDevModeStage),
+			listen("pairing-pin-request", showPinPrompt),
+			listen("operation", trackOperation),
+		]);
 		fetchDevices();
+
+		return () => {
+			clearTimeout(pinTimer);
+			unlisteners.then((fns) => fns.forEach((unlisten) => unlisten()));
+		};
 	}, []);
 
 	return (
@@ -120,11 +346,11 @@ export default function HomePage() {
 					<DialogContentText id="alert-dialog-description">
 						Auto Capture relies on developer mode to your iOS device to allow
 						screen capture functionality. You do not appear to have developer
-						mode enabled on your iOS device. Please enable developer mode in the
-						Settings app under Privacy & Security &gt; Developer Mode. After
-						enabling, developer mode, your device will restart and prompt you
-						with additional confirmation dialogs. Once complete, please
-						re-attempt the device setup process.
+						mode enabled on your iOS device, so it is being turned on now. Your
+						device will restart and then ask you to confirm. If it has a
+						passcode, enable developer mode yourself in the Settings app under
+						Privacy &amp; Security &gt; Developer Mode. Setup continues on its
+						own once developer mode is on.
 					</DialogContentText>
 				</DialogContent>
 				<DialogActions>
@@ -133,6 +359,30 @@ export default function HomePage() {
 					</Button>
 				</DialogActions>
 			</Dialog>
+			<Dialog open={pinRequest !== null} onClose={() => submitPin(null)}>
+				<DialogTitle>{"Pairing code"}</DialogTitle>
+				<DialogContent>
+					<DialogContentText>
+						Enter the code shown on your device.
+					</DialogContentText>
+					<TextField
+						autoFocus
+						fullWidth
+						margin="dense"
+						value={pin}
+						onChange={(e) =>
+							setPin(e.target.value.replace(/\D/g, "").slice(0, 6))
+						}
+						inputProps={{ inputMode: "numeric" }}
+					/>
+				</DialogContent>
+				<DialogActions>
+					<Button onClick={() => submitPin(null)}>Cancel</Button>
+					<Button disabled={pin.length !== 6} onClick={() => submitPin(pin)}>
+						Pair
+					</Button>
+				</DialogActions>
+			</Dialog>
 			<Grid
 				container
 				spacing={0}
@@ -259,8 +509,15 @@ export default function HomePage() {
 								label="Device"
 								onChange={(event) => setUDID(event.target.value)}
 							>
-								{Object.keys(devices).map((key) => (
-									<MenuItem value={devices[key]}>{key}</MenuItem>
+								{devices.map((device) => (
+									<MenuItem key={device.udid} value={device.udid}>
+										{device.name ?? device.udid}
+										{device.modelName ? ` (${device.modelName})` : ""}
+										{device.connectionType === "network"
+											? ` [Wi-Fi ${device.address}]`
+											: ""}
+										{device.error ? ` - ${device.error.message}` : ""}
+									</MenuItem>
 								))}
 							</Select>
 						</FormControl>
@@ -292,16 +549,60 @@ export default function HomePage() {
 								variant: "success",
 							});
 						} catch (e) {
+							if (e.code === "cancelled") {
+								enqueueSnackbar("Device setup cancelled.", { variant: "info" });
+								return;
+							}
 							// notistack error notification
 							console.error("Error setting up device:", e);
-							enqueueSnackbar("Failed to setup device: " + e.message, {
-								variant: "error",
-							});
+							// commands reject with { code, message, remediation }
+							const remediation = e.remediation ? ` ${e.remediation}` : "";
+							enqueueSnackbar(
+								`Failed to setup device: ${e.message}${remediation}`,
+								{ variant: "error" }
+							);
 						}
 					}}
 				>
 					Setup Device
 				</Button>
+				<Button
+					disabled={devices.length < 2}
+					onClick={() =>
+						setupAllDevices().catch((e) =>
+							enqueueSnackbar(`Failed to setup devices: ${e.message}`, {
+								variant: "error",
+							})
+						)
+					}
+				>
+					Setup All Devices
+				</Button>
+				{setupProgress && (
+					<Box sx={{ width: "100%", maxWidth: 400 }}>
+						<LinearProgress
+							variant="determinate"
+							value={(setupProgress.index / setupProgress.total) * 100}
+						/>
+						<Typography variant="caption">
+							{`Step ${setupProgress.index + 1} of ${setupProgress.total}: ${setupStageLabels[setupProgress.stage]}`}
+						</Typography>
+					</Box>
+				)}
+				{runningOperations.length > 0 && (
+					<Button
+						color="secondary"
+						onClick={() =>
+							runningOperations.forEach((operationId) =>
+								invoke("cancel_operation", { operationId }).catch((e) =>
+									console.error("Error cancelling:", e)
+								)
+							)
+						}
+					>
+						Cancel
+					</Button>
+				)}
 			</Grid>
 		</div>
 	);